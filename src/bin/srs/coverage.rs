@@ -0,0 +1,42 @@
+use anyhow::anyhow;
+
+use smooth_river_stones::coverage::report;
+use smooth_river_stones::engine::State;
+use smooth_river_stones::script::Script;
+
+use crate::{load_zone, Options};
+
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    let paths = options.rest();
+    if paths.is_empty() {
+        return Err(anyhow!("missing the saves or test scripts to measure"));
+    }
+
+    // Saves are taken as they are, scripts are played to get one
+    let mut playthroughs = Vec::new();
+    for path in paths {
+        let source = std::fs::read_to_string(path).map_err(|err| anyhow!("could not read {}: {}", path, err))?;
+        if path.ends_with(".json") {
+            let state: State = serde_json::from_str(&source).map_err(|err| anyhow!("could not read {}: {}", path, err))?;
+            playthroughs.push(state);
+        } else {
+            let script = Script::parse(path.clone(), source)?;
+            match script.run(zone.clone()) {
+                Ok(state) => playthroughs.push(state),
+                Err(failure) => eprintln!("skipping {}, it failed at {}", path, failure),
+            }
+        }
+    }
+
+    let report = report(&zone, &playthroughs);
+    for miss in &report.missed {
+        println!("{}", miss);
+    }
+    println!();
+    println!("scenes    {}", report.scenes);
+    println!("lines     {}", report.lines);
+    println!("links     {}", report.links);
+    println!("filters   {}", report.outcomes);
+    Ok(())
+}
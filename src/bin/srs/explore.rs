@@ -0,0 +1,41 @@
+use anyhow::anyhow;
+
+use smooth_river_stones::explore::{explore, Limits, Outcome};
+
+use crate::{load_zone, Options};
+
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    let defaults = Limits::default();
+    let limits = Limits {
+        depth: options.number("depth")?.map_or(defaults.depth, |depth| depth as usize),
+        states: options.number("states")?.map_or(defaults.states, |states| states as usize),
+        seed: options.number("seed")?.unwrap_or(defaults.seed),
+    };
+
+    let report = explore(zone, limits);
+    for finding in &report.findings {
+        println!("{}", finding);
+        if finding.choices.is_empty() {
+            println!("    from the start");
+        } else {
+            println!("    after: {}", finding.choices.join(", "));
+        }
+    }
+    println!("explored {} states", report.states);
+    if report.unexplored > 0 {
+        println!("{} states were left unexplored, raise --depth or --states to look further", report.unexplored);
+    }
+    if report.rolled {
+        println!("dice were rolled with seed {} only, other --seed values can lead elsewhere", limits.seed);
+    }
+
+    let problems = report.findings.iter().filter(|finding| finding.is_problem()).count();
+    if problems > 0 {
+        return Err(anyhow!("found {} problems", problems));
+    }
+    if !report.findings.iter().any(|finding| matches!(finding.outcome, Outcome::Ending(_))) {
+        return Err(anyhow!("no ending can be reached"));
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+
+use smooth_river_stones::explore::random_walk;
+
+use crate::{load_zone, Options};
+
+// A problem along with the shortest way found to reach it
+struct Problem {
+    seed: u64,
+    choices: Vec<String>,
+}
+
+fn note(problems: &mut BTreeMap<String, Problem>, message: String, seed: u64, choices: &[String]) {
+    let shorter = problems.get(&message).is_none_or(|problem| choices.len() < problem.choices.len());
+    if shorter {
+        problems.insert(message, Problem { seed, choices: choices.to_vec() });
+    }
+}
+
+/// Play a zone over and over with random choices. The walks happen in a
+/// second copy of this program, so one that overflows the stack can be
+/// reported with the choices that led to it and the rest carried on with.
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = options.zone()?;
+    load_zone(zone)?;
+    let runs = options.number("runs")?.unwrap_or(1000);
+    let depth = options.number("depth")?.unwrap_or(200);
+    let seed = match options.number("seed")? {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+    };
+    println!("fuzzing {} with {} runs from seed {}", zone, runs, seed);
+
+    let mut problems = BTreeMap::new();
+    let mut endings = BTreeSet::new();
+    let mut first = 0;
+    while first < runs {
+        let mut worker = Command::new(std::env::current_exe()?)
+            .args(["fuzz-worker", zone])
+            .args(["--seed", &seed.to_string(), "--depth", &depth.to_string()])
+            .args(["--first", &first.to_string(), "--runs", &runs.to_string()])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let mut current = None;
+        let mut choices = Vec::new();
+        let output = worker.stdout.take().ok_or_else(|| anyhow!("could not read from the fuzzing process"))?;
+        for line in BufReader::new(output).lines() {
+            let line = line?;
+            match line.split_once(' ') {
+                Some(("run", run)) => {
+                    current = Some(run.parse::<u64>()?);
+                    choices.clear();
+                },
+                Some(("choose", choice)) => choices.push(choice.to_string()),
+                Some(("ending", ending)) => {
+                    endings.insert(ending.to_string());
+                },
+                Some(("problem", problem)) => {
+                    note(&mut problems, problem.to_string(), seed.wrapping_add(current.unwrap_or(0)), &choices);
+                },
+                _ => {},
+            }
+        }
+
+        let status = worker.wait()?;
+        if status.success() {
+            break;
+        }
+        // Only a crash that can't be caught, like a stack overflow, gets here.
+        // The status says how it went down, with the signal on unix.
+        let run = current.ok_or_else(|| anyhow!("the fuzzing process stopped before starting, {}", status))?;
+        let message = match choices.last() {
+            Some(choice) => format!("crash ({}) on choosing '{}'", status, choice),
+            None => format!("crash ({}) at the start", status),
+        };
+        note(&mut problems, message, seed.wrapping_add(run), &choices);
+        first = run + 1;
+    }
+
+    for ending in &endings {
+        println!("{}", ending);
+    }
+    for (message, problem) in &problems {
+        println!("{}", message);
+        if problem.choices.is_empty() {
+            println!("    seed {}, from the start", problem.seed);
+        } else {
+            println!("    seed {}, after: {}", problem.seed, problem.choices.join(", "));
+        }
+    }
+    println!("reached {} endings", endings.len());
+    if !problems.is_empty() {
+        return Err(anyhow!("found {} problems", problems.len()));
+    }
+    Ok(())
+}
+
+// The other side of run, reporting each step as it goes
+pub fn worker(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    let seed = options.number("seed")?.unwrap_or(0);
+    let depth = options.number("depth")?.unwrap_or(200) as usize;
+    let first = options.number("first")?.unwrap_or(0);
+    let runs = options.number("runs")?.unwrap_or(1000);
+
+    for run in first..runs {
+        println!("run {}", run);
+        let finding = random_walk(zone.clone(), seed.wrapping_add(run), depth, |choice| println!("choose {}", choice));
+        match finding {
+            Some(finding) if finding.is_problem() => println!("problem {}", finding),
+            Some(finding) => println!("ending {}", finding),
+            None => {},
+        }
+    }
+    Ok(())
+}
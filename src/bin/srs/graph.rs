@@ -0,0 +1,13 @@
+use anyhow::anyhow;
+
+use crate::{load_zone, Options};
+
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    match options.get("format").unwrap_or("dot") {
+        "dot" => print!("{}", zone.to_dot()),
+        "mermaid" => print!("{}", zone.to_mermaid()),
+        format => return Err(anyhow!("--format should be dot or mermaid, not '{}'", format)),
+    }
+    Ok(())
+}
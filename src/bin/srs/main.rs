@@ -0,0 +1,160 @@
+mod coverage;
+mod explore;
+mod fuzz;
+mod graph;
+mod play;
+mod test;
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+
+use smooth_river_stones::zone::lint::lint;
+use smooth_river_stones::zone::{assemble, Zone, ZoneFile};
+
+const USAGE: &str = "usage: srs <command> [options]
+
+commands:
+    play <zone> [--save <file>] [--seed <number>]
+        Play a zone in the terminal, keeping progress in the save file.
+    test <zone> <script>...
+        Run scripted playthroughs against a zone.
+    explore <zone> [--depth <number>] [--states <number>] [--seed <number>]
+        Try every choice to find the endings, softlocks, loops and crashes.
+    coverage <zone> <save or script>...
+        Show which parts of a zone the playthroughs never reached.
+    fuzz <zone> [--runs <number>] [--seed <number>] [--depth <number>]
+        Play a zone many times with random choices, looking for crashes.
+    graph <zone> [--format dot|mermaid]
+        Print how the scenes connect, for Graphviz or Mermaid.";
+
+// Paths in `*include` are relative to the including file
+fn resolve(from: &str, path: &str) -> String {
+    let joined = Path::new(from).parent().unwrap_or_else(|| Path::new("")).join(path);
+    let mut parts: Vec<Component> = Vec::new();
+    for part in joined.components() {
+        match part {
+            Component::CurDir => {},
+            Component::ParentDir if matches!(parts.last(), Some(Component::Normal(_))) => {
+                parts.pop();
+            },
+            part => parts.push(part),
+        }
+    }
+    parts.iter().collect::<PathBuf>().to_string_lossy().into_owned()
+}
+
+/// Read a zone file and everything it includes from disk.
+pub fn load_zone(path: &str) -> Result<Rc<Zone>, anyhow::Error> {
+    let mut files = HashMap::new();
+    let mut pending = vec![path.to_string()];
+    while let Some(name) = pending.pop() {
+        if files.contains_key(&name) {
+            continue;
+        }
+        let source = match std::fs::read_to_string(&name) {
+            Ok(source) => source,
+            // Missing includes are reported by assemble, where they are written
+            Err(_) if name != path => continue,
+            Err(err) => return Err(anyhow!("could not read {}: {}", name, err)),
+        };
+        let file = ZoneFile::parse(name.clone(), source)?;
+        pending.extend(file.includes().into_iter().map(|include| resolve(&name, include)));
+        files.insert(name, file);
+    }
+
+    let zone = assemble(path, &files, resolve)?;
+    for diagnostic in lint(&zone) {
+        eprintln!("{}", diagnostic);
+    }
+    Ok(zone)
+}
+
+/// Pull `--name value` options out of the arguments, leaving the rest in order.
+pub struct Options {
+    positional: Vec<String>,
+    named: HashMap<String, String>,
+}
+
+impl Options {
+    fn parse(args: Vec<String>) -> Result<Self, anyhow::Error> {
+        let mut positional = Vec::new();
+        let mut named = HashMap::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => match args.next() {
+                    Some(value) => {
+                        named.insert(name.to_string(), value);
+                    },
+                    None => return Err(anyhow!("missing a value for --{}", name)),
+                },
+                None => positional.push(arg),
+            }
+        }
+        Ok(Self { positional, named })
+    }
+
+    pub fn zone(&self) -> Result<&str, anyhow::Error> {
+        self.positional.first().map(String::as_str).ok_or_else(|| anyhow!("missing the zone file to load"))
+    }
+
+    /// Everything given after the zone file.
+    pub fn rest(&self) -> &[String] {
+        self.positional.get(1..).unwrap_or_default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.named.get(name).map(String::as_str)
+    }
+
+    pub fn number(&self, name: &str) -> Result<Option<u64>, anyhow::Error> {
+        match self.get(name) {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(anyhow!("--{} should be a number, not '{}'", name, value)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    let options = match Options::parse(args.collect()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            exit(2);
+        },
+    };
+
+    // Exploring and fuzzing report engine panics as findings, keep them from
+    // also being printed as they happen
+    if matches!(command.as_deref(), Some("explore") | Some("fuzz-worker")) {
+        std::panic::set_hook(Box::new(|_| {}));
+    }
+
+    let result = match command.as_deref() {
+        Some("play") => play::run(&options),
+        Some("test") => test::run(&options),
+        Some("explore") => explore::run(&options),
+        Some("coverage") => coverage::run(&options),
+        Some("fuzz") => fuzz::run(&options),
+        Some("fuzz-worker") => fuzz::worker(&options),
+        Some("graph") => graph::run(&options),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        },
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
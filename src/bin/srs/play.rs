@@ -0,0 +1,200 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use smooth_river_stones::engine::{Engine, Fragment, State, Status, StoryError};
+use smooth_river_stones::zone::TextLink;
+
+use crate::{load_zone, Options};
+
+const HELP: &str = "Press enter to read on, or type the number of a choice to take it.
+    inventory   list what you are carrying
+    vars        list the story's variables
+    save        write progress to the save file
+    restart     start the story over
+    quit        stop playing";
+
+// Links are numbered in the order they are shown so they can be picked
+fn render(fragments: &[Fragment], links: &mut Vec<TextLink>) -> String {
+    let text: String = fragments.iter().map(|fragment| match fragment {
+        Fragment::Text(text) => text.clone(),
+        Fragment::Link(link) => {
+            links.push(link.clone());
+            format!("[{}] {}", links.len(), link.text)
+        },
+        Fragment::Locked(link, reason) if reason.is_empty() => format!("[-] {}", link.text),
+        Fragment::Locked(link, reason) => format!("[-] {} ({})", link.text, reason),
+    }).collect();
+    text.trim().to_string()
+}
+
+fn broke(error: &StoryError) {
+    println!("\nThe story went wrong: {}", error);
+    println!("Play has gone back to before it happened.");
+}
+
+struct Player {
+    engine: Engine,
+    save: Option<String>,
+}
+
+impl Player {
+    fn save(&self) -> Result<(), anyhow::Error> {
+        if let Some(path) = &self.save {
+            std::fs::write(path, serde_json::to_string(self.engine.state())?)?;
+        }
+        Ok(())
+    }
+
+    fn show_inventory(&self) {
+        let state = self.engine.state();
+        if state.inventory.is_empty() {
+            println!("You aren't carrying anything.");
+        }
+        let mut items: Vec<_> = state.inventory.iter().collect();
+        items.sort();
+        for (key, count) in items {
+            let item = state.items.get(key);
+            let name = item.and_then(|item| item.name.clone()).unwrap_or_else(|| key.clone());
+            println!("  {} x {}", count, name);
+            if let Some(details) = item.and_then(|item| item.details.as_ref()) {
+                println!("      {}", details);
+            }
+        }
+    }
+
+    fn show_values(&self) {
+        let state = self.engine.state();
+        if state.values.is_empty() {
+            println!("No variables have been set.");
+        }
+        let mut values: Vec<_> = state.values.iter().collect();
+        values.sort();
+        for (name, value) in values {
+            println!("  {} = {}", name, value);
+        }
+    }
+}
+
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    let save = options.get("save").map(String::from);
+    let state = match &save {
+        Some(path) if Path::new(path).exists() => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        _ => match options.number("seed")? {
+            Some(seed) => State::with_seed(&zone, seed),
+            None => State::new(&zone),
+        },
+    };
+
+    if let Some(title) = &zone.meta.title {
+        println!("{}", title);
+        if let Some(author) = &zone.meta.author {
+            println!("by {}", author);
+        }
+        println!();
+    }
+
+    let mut player = Player {
+        engine: Engine::with_state(zone, state),
+        save,
+    };
+    let stdin = io::stdin();
+    let mut input = stdin.lock().lines();
+    let mut fresh = true;
+    let mut links = Vec::new();
+    loop {
+        let view = player.engine.view();
+        if let (true, Err(error)) = (fresh, &view) {
+            // There is no good state to go back to, the story can only start over
+            println!("The story went wrong: {}", error);
+            println!("Type 'restart' to start over.");
+            links.clear();
+            fresh = false;
+        }
+        if let (true, Ok(view)) = (fresh, &view) {
+            links.clear();
+            let lines: Vec<String> = view.lines.iter()
+                .map(|line| render(line, &mut links))
+                .filter(|line| !line.is_empty())
+                .collect();
+
+            // Nothing to read on this line, carry straight on
+            if lines.is_empty() && !view.branch {
+                match player.engine.step() {
+                    Ok(true) => continue,
+                    Ok(false) => {},
+                    Err(error) => broke(&error),
+                }
+            }
+            for line in lines {
+                println!("{}", line);
+            }
+            match player.engine.state().status {
+                Status::Running => {},
+                Status::Finished => println!("\n-- The End --"),
+                Status::Reset => println!("\n-- Press enter to start over --"),
+            }
+            fresh = false;
+        }
+
+        print!("> ");
+        io::stdout().flush()?;
+        let command = match input.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+
+        match command.trim() {
+            "" => match player.engine.state().status {
+                Status::Running => match &view {
+                    Err(_) => println!("Type 'restart' to start over."),
+                    Ok(view) if view.branch => println!("Pick a choice by its number, or type 'help'."),
+                    Ok(_) => {
+                        if let Err(error) = player.engine.step() {
+                            broke(&error);
+                        }
+                        player.save()?;
+                        fresh = true;
+                    },
+                },
+                Status::Finished => return Ok(()),
+                Status::Reset => {
+                    player.engine.restart();
+                    player.save()?;
+                    fresh = true;
+                },
+            },
+            "i" | "inventory" => player.show_inventory(),
+            "v" | "vars" => player.show_values(),
+            "s" | "save" => match &player.save {
+                Some(path) => {
+                    player.save()?;
+                    println!("Saved to {}", path);
+                },
+                None => println!("Start with --save <file> to keep progress."),
+            },
+            "r" | "restart" => {
+                player.engine.restart();
+                player.save()?;
+                println!();
+                fresh = true;
+            },
+            "q" | "quit" => return Ok(()),
+            "h" | "help" | "?" => println!("{}", HELP),
+            other => match other.parse::<usize>() {
+                Ok(number) => match links.get(number.wrapping_sub(1)) {
+                    Some(link) => {
+                        println!("\n{}\n", link.text);
+                        if let Err(error) = player.engine.choose(link) {
+                            broke(&error);
+                        }
+                        player.save()?;
+                        fresh = true;
+                    },
+                    None => println!("There is no choice {}.", number),
+                },
+                Err(_) => println!("Unknown command '{}', type 'help' for a list.", other),
+            },
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use anyhow::anyhow;
+
+use smooth_river_stones::script::Script;
+
+use crate::{load_zone, Options};
+
+pub fn run(options: &Options) -> Result<(), anyhow::Error> {
+    let zone = load_zone(options.zone()?)?;
+    let scripts = options.rest();
+    if scripts.is_empty() {
+        return Err(anyhow!("missing the test scripts to run"));
+    }
+
+    let mut failed = 0;
+    for path in scripts {
+        let source = std::fs::read_to_string(path).map_err(|err| anyhow!("could not read {}: {}", path, err))?;
+        let script = Script::parse(path.clone(), source)?;
+        match script.run(zone.clone()) {
+            Ok(_) => println!("ok      {}", path),
+            Err(failure) => {
+                failed += 1;
+                println!("FAILED  {}\n        {}", path, failure);
+            },
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{} of {} scripts failed", failed, scripts.len()));
+    }
+    println!("all {} scripts passed", scripts.len());
+    Ok(())
+}
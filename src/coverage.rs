@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::engine::{Coverage, State};
+use crate::zone::{Line, LineMessage, TextPart, Zone};
+
+/// How many of something were exercised out of how many there are.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tally {
+    pub hit: usize,
+    pub total: usize,
+}
+
+impl Tally {
+    fn count(&mut self, hit: bool) {
+        self.total += 1;
+        if hit {
+            self.hit += 1;
+        }
+    }
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = (self.hit * 100).checked_div(self.total).unwrap_or(100);
+        write!(f, "{}/{} ({}%)", self.hit, self.total, percent)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub scenes: Tally,
+    pub lines: Tally,
+    pub links: Tally,
+    /// Each filter counts twice, once for being true and once for being false.
+    pub outcomes: Tally,
+    /// The parts of the zone no playthrough exercised.
+    pub missed: Vec<LineMessage>,
+}
+
+/// Work out which scenes, lines, links and filter outcomes of a zone were
+/// exercised across a set of playthroughs.
+pub fn report(zone: &Zone, playthroughs: &[State]) -> Report {
+    let mut coverage = Coverage::default();
+    let mut visited = HashSet::new();
+    for state in playthroughs {
+        coverage.merge(&state.coverage);
+        visited.extend(state.visits.keys().cloned());
+        visited.extend(state.coverage.lines.keys().cloned());
+    }
+    if !playthroughs.is_empty() {
+        visited.insert(zone.start());
+    }
+
+    let mut report = Report::default();
+    let mut missed = Vec::new();
+    for scene in zone.scenes() {
+        let seen = visited.contains(&scene.label);
+        report.scenes.count(seen);
+        let mut miss = |line: usize, message: String| missed.push(LineMessage { file: scene.file.clone(), line, message });
+        if !seen {
+            miss(scene.source_line, format!("scene '{}' was never visited", scene.label));
+        }
+
+        let lines = coverage.lines.get(&scene.label);
+        let links = coverage.links.get(&scene.label);
+        let passed = coverage.passed.get(&scene.label);
+        let failed = coverage.failed.get(&scene.label);
+        for (index, line) in scene.lines.iter().enumerate() {
+            let source = scene.line_sources[index];
+            let (filter, kind) = match line {
+                Line::TextLine(text) => (text.filter.as_ref(), "filter"),
+                Line::CommandLine(command) => (command.filter.as_ref(), "filter"),
+                Line::Jump(jump) => (jump.unless.as_ref(), "condition"),
+            };
+
+            // A scene that was never visited is already reported as a whole
+            if !matches!(line, Line::Jump(_)) {
+                let hit = lines.is_some_and(|lines| lines.contains(&index));
+                report.lines.count(hit);
+                if seen && !hit {
+                    let message = match line {
+                        Line::CommandLine(_) => "command never ran",
+                        _ => "line was never shown",
+                    };
+                    miss(source, String::from(message));
+                }
+            }
+
+            if let Line::TextLine(text) = line {
+                for part in &text.parts {
+                    if let TextPart::Link(link) = part {
+                        let hit = links.is_some_and(|links| links.contains(&link.destination));
+                        report.links.count(hit);
+                        if seen && !hit {
+                            miss(source, format!("link to '{}' was never taken", link.destination));
+                        }
+                    }
+                }
+            }
+
+            if let Some(filter) = filter {
+                for (outcome, hits) in [(true, passed), (false, failed)] {
+                    let hit = hits.is_some_and(|hits| hits.contains(&index));
+                    report.outcomes.count(hit);
+                    if seen && !hit {
+                        miss(source, format!("{} '{}' was never {}", kind, filter.operation, outcome));
+                    }
+                }
+            }
+        }
+    }
+
+    missed.sort_by(|a: &LineMessage, b: &LineMessage| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    missed.dedup();
+    report.missed = missed;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::zone::build_world;
+
+    const ZONE: &str = "default: ??\n    ? Which way?\n    (gold > 0) You have gold.\n    [left | Go left.]\n    [right | Go right.]\n\n    left:\n        The left path.\n        *set gold = 3\n        *next default\n\n    right:\n        The right path.\n        *end\n\ncave:\n    Never reached.\n";
+
+    fn played(scripts: &[&str]) -> Report {
+        let zone = build_world(ZONE.to_string()).unwrap();
+        let playthroughs: Vec<State> = scripts.iter()
+            .map(|script| Script::parse(String::from("test"), script.to_string()).unwrap().run(zone.clone()).unwrap())
+            .collect();
+        report(&zone, &playthroughs)
+    }
+
+    fn missed(report: &Report) -> Vec<String> {
+        report.missed.iter().map(|miss| miss.to_string()).collect()
+    }
+
+    #[test]
+    fn playthroughs_are_tallied() {
+        let report = played(&["choose right\nexpect status finished\n"]);
+        assert_eq!(report.scenes, Tally { hit: 2, total: 4 });
+        assert_eq!(report.lines, Tally { hit: 5, total: 10 });
+        assert_eq!(report.links, Tally { hit: 1, total: 2 });
+        assert_eq!(report.outcomes, Tally { hit: 1, total: 2 });
+        assert_eq!(missed(&report), vec![
+            "line 3: line was never shown",
+            "line 3: filter 'gold > 0' was never true",
+            "line 4: link to 'default.left' was never taken",
+            "line 7: scene 'default.left' was never visited",
+            "line 16: scene 'cave' was never visited",
+        ]);
+
+        // Coming back round with gold shows the filtered line and passes its filter
+        let report = played(&["choose right\n", "choose left\nchoose right\n"]);
+        assert_eq!(report.scenes, Tally { hit: 3, total: 4 });
+        assert_eq!(report.lines, Tally { hit: 9, total: 10 });
+        assert_eq!(report.links, Tally { hit: 2, total: 2 });
+        assert_eq!(report.outcomes, Tally { hit: 2, total: 2 });
+        assert_eq!(missed(&report), vec!["line 16: scene 'cave' was never visited"]);
+        assert_eq!(report.scenes.to_string(), "3/4 (75%)");
+    }
+
+    #[test]
+    fn nothing_played_covers_nothing() {
+        let report = played(&[]);
+        assert_eq!(report.scenes, Tally { hit: 0, total: 4 });
+        assert_eq!(report.lines.hit, 0);
+        assert_eq!(report.missed.len(), 4);
+        assert_eq!(Tally::default().to_string(), "0/0 (100%)");
+    }
+}
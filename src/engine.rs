@@ -0,0 +1,1241 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::zone::{Command, FilterOperation, Interpolation, Item, ItemField, Jump, Line, LineFilter, LinkKind, Ops, RollCommand, Scene, SetCommand, SetOperator, TextLine, TextLink, TextPart, Zone};
+
+#[derive(Debug, Clone, PartialEq, Hash, Deserialize, Serialize)]
+pub enum Status {
+    Running,
+    Finished,
+    Reset
+}
+
+/// A deterministic random sequence. Only the seed and how far along the
+/// sequence we are need to be saved to pick up where a session left off.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Random {
+    pub seed: u64,
+    pub position: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, position: 0 }
+    }
+
+    // splitmix64
+    fn next(&mut self) -> u64 {
+        self.position += 1;
+        let mut value = self.seed.wrapping_add(self.position.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^ (value >> 31)
+    }
+
+    /// A number between the two bounds, including both.
+    pub fn range(&mut self, low: i32, high: i32) -> i32 {
+        let (low, high) = if low <= high { (low, high) } else { (high, low) };
+        let span = (high as i64 - low as i64 + 1) as u64;
+        (low as i64 + (self.next() % span) as i64) as i32
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn fresh_seed() -> u64 {
+    (js_sys::Math::random() * u64::MAX as f64) as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn fresh_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Where to pick up again once a `*call`ed scene returns.
+#[derive(Debug, Clone, PartialEq, Hash, Deserialize, Serialize)]
+pub struct ReturnPoint {
+    pub scene: String,
+    pub line: usize,
+}
+
+/// Which parts of a zone a playthrough has exercised, by scene label and the
+/// index of the line within the scene.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Coverage {
+    /// Text lines read and commands run.
+    pub lines: HashMap<String, BTreeSet<usize>>,
+    /// Destinations of links taken.
+    pub links: HashMap<String, BTreeSet<String>>,
+    /// Lines whose filter was true at least once.
+    pub passed: HashMap<String, BTreeSet<usize>>,
+    /// Lines whose filter was false at least once.
+    pub failed: HashMap<String, BTreeSet<usize>>,
+}
+
+impl Coverage {
+    fn hit(&mut self, scene: &str, line: usize) {
+        // Runs on every line play passes, so only allocate for a new scene
+        match self.lines.get_mut(scene) {
+            Some(lines) => {
+                lines.insert(line);
+            },
+            None => {
+                self.lines.insert(scene.to_string(), BTreeSet::from([line]));
+            },
+        }
+    }
+
+    fn link(&mut self, scene: &str, destination: &str) {
+        self.links.entry(scene.to_string()).or_default().insert(destination.to_string());
+    }
+
+    fn filter(&mut self, scene: &str, line: usize, outcome: bool) {
+        let outcomes = if outcome { &mut self.passed } else { &mut self.failed };
+        outcomes.entry(scene.to_string()).or_default().insert(line);
+    }
+
+    /// Add everything another playthrough exercised.
+    pub fn merge(&mut self, other: &Coverage) {
+        let sets = [
+            (&mut self.lines, &other.lines),
+            (&mut self.passed, &other.passed),
+            (&mut self.failed, &other.failed),
+        ];
+        for (mine, theirs) in sets {
+            for (scene, lines) in theirs {
+                mine.entry(scene.clone()).or_default().extend(lines);
+            }
+        }
+        for (scene, links) in &other.links {
+            self.links.entry(scene.clone()).or_default().extend(links.iter().cloned());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct State {
+    pub log: VecDeque<String>,
+    pub scene: String,
+    pub line: usize,
+    pub inventory: HashMap<String, i32>,
+    pub items: HashMap<String, Item>,
+    pub visits: HashMap<String, u32>,
+    pub values: HashMap<String, i32>,
+    pub status: Status,
+    #[serde(default)]
+    pub random: Random,
+    #[serde(default)]
+    pub stack: Vec<ReturnPoint>,
+    #[serde(default)]
+    pub taken: HashSet<String>,
+    #[serde(default)]
+    pub coverage: Coverage,
+    /// Set until an engine has run the header and brought play to the first
+    /// line to show.
+    #[serde(default)]
+    pub fresh: bool,
+}
+
+impl State {
+    pub fn new(zone: &Zone) -> Self {
+        Self::with_seed(zone, fresh_seed())
+    }
+
+    /// Start a playthrough whose random rolls are always the same.
+    pub fn with_seed(zone: &Zone, seed: u64) -> Self {
+        let init = &zone.initialize;
+        Self {
+            log: Default::default(),
+            scene: zone.start(),
+            line: 0,
+            inventory: Default::default(),
+            items: init.iter().fold(Default::default(), |mut acc, val|{
+                if let Command::SetItem(item) = val {
+                    acc.insert(item.key.clone(), item.clone());
+                }
+                acc
+            }),
+            visits: Default::default(),
+            values: Default::default(),
+            status: Status::Running,
+            random: Random::new(seed),
+            stack: Default::default(),
+            taken: Default::default(),
+            coverage: Default::default(),
+            fresh: true,
+        }
+    }
+
+    /// Everything about this state that can change how the rest of the story
+    /// plays, written out so two states that will play the same compare equal.
+    pub fn key(&self, zone: &Zone) -> String {
+        let visits: BTreeMap<_, _> = self.visits.iter().filter(|(scene, _)| zone.counted().contains(*scene)).collect();
+        let inventory: BTreeMap<_, _> = self.inventory.iter().collect();
+        let items: BTreeMap<_, _> = self.items.iter().collect();
+        let values: BTreeMap<_, _> = self.values.iter().collect();
+        let taken: BTreeSet<_> = self.taken.iter().collect();
+        serde_json::to_string(&(
+            &self.scene, self.line, &self.status, visits, inventory, items, values, taken, &self.stack, &self.random,
+        )).unwrap_or_default()
+    }
+
+    // A cheaper stand in for `key`, taken on every scene play arrives in while
+    // looking for a line to show. Maps are added up entry by entry so the
+    // order they happen to be stored in doesn't matter.
+    fn fingerprint(&self, zone: &Zone) -> u64 {
+        fn hash(value: impl Hash) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+        let mut total = hash((&self.scene, self.line, &self.status, &self.stack, self.random.seed, self.random.position));
+        for scene in zone.counted() {
+            total = total.wrapping_add(hash(("visits", scene, self.visits.get(scene))));
+        }
+        for entry in &self.inventory {
+            total = total.wrapping_add(hash(("inventory", entry)));
+        }
+        for item in self.items.values() {
+            total = total.wrapping_add(hash(("item", &item.key, &item.name, &item.details)));
+        }
+        for entry in &self.values {
+            total = total.wrapping_add(hash(("value", entry)));
+        }
+        for link in &self.taken {
+            total = total.wrapping_add(hash(("taken", link)));
+        }
+        total
+    }
+
+    fn apply_set(&mut self, cmd: &SetCommand) -> Result<(), String> {
+        let value = self.evaluate_committed(&cmd.value)?;
+        let current = *self.values.get(&cmd.name).unwrap_or(&0);
+        let value = match cmd.operator {
+            SetOperator::Assign => Some(value),
+            SetOperator::Add => current.checked_add(value),
+            SetOperator::Sub => current.checked_sub(value),
+            SetOperator::Mul => current.checked_mul(value),
+        };
+        let value = value.ok_or_else(|| format!("'{}' went out of range", cmd.name))?;
+        self.values.insert(cmd.name.clone(), value);
+        Ok(())
+    }
+
+    fn apply_roll(&mut self, cmd: &RollCommand) {
+        let mut total = cmd.modifier as i64;
+        for _ in 0..cmd.count {
+            total += self.random.range(1, cmd.sides.max(1).min(i32::MAX as u32) as i32) as i64;
+        }
+        self.values.insert(cmd.name.clone(), total.clamp(i32::MIN as i64, i32::MAX as i64) as i32);
+    }
+
+    /// Evaluate an expression without consuming any random numbers, so the
+    /// same expression keeps giving the same answer until a command rolls.
+    pub fn evaluate(&self, op: &FilterOperation) -> Result<i32, String> {
+        self.evaluate_with(op, &mut self.random.clone())
+    }
+
+    fn evaluate_committed(&mut self, op: &FilterOperation) -> Result<i32, String> {
+        let mut random = self.random.clone();
+        let value = self.evaluate_with(op, &mut random)?;
+        self.random = random;
+        Ok(value)
+    }
+
+    /// Evaluate an expression, taking any random numbers from `random`.
+    /// Fails on a division by zero or a result too large to hold.
+    pub fn evaluate_with(&self, op: &FilterOperation, random: &mut Random) -> Result<i32, String> {
+        Ok(match op {
+            FilterOperation::OperatorCall(call) => {
+                let left = self.evaluate_with(&call.left, random)?;
+                // Only look at the right hand side when it decides the answer
+                match call.operator {
+                    Ops::And if left == 0 => return Ok(0),
+                    Ops::Or if left != 0 => return Ok(left),
+                    _ => {},
+                }
+                let right = self.evaluate_with(&call.right, random)?;
+                let out_of_range = || format!("'{}' went out of range", op);
+                match call.operator {
+                    Ops::Add => left.checked_add(right).ok_or_else(out_of_range)?,
+                    Ops::Sub => left.checked_sub(right).ok_or_else(out_of_range)?,
+                    Ops::Mul => left.checked_mul(right).ok_or_else(out_of_range)?,
+                    Ops::Div if right == 0 => return Err(format!("'{}' divides by zero", op)),
+                    Ops::Div => left.checked_div(right).ok_or_else(out_of_range)?,
+                    Ops::Gt => (left > right) as i32,
+                    Ops::Gte => (left >= right) as i32,
+                    Ops::Lt => (left < right) as i32,
+                    Ops::Lte => (left <= right) as i32,
+                    Ops::Eq => (left == right) as i32,
+                    Ops::Ne => (left != right) as i32,
+                    Ops::And | Ops::Or => right,
+                }
+            },
+            FilterOperation::Random(call) => {
+                let low = self.evaluate_with(&call.low, random)?;
+                let high = self.evaluate_with(&call.high, random)?;
+                random.range(low, high)
+            },
+            FilterOperation::IntLiteral(lit) => *lit,
+            FilterOperation::CountVisits(visit) => (*self.visits.get(visit).unwrap_or(&0)).min(i32::MAX as u32) as i32,
+            FilterOperation::CountItems(item) => *self.inventory.get(item).unwrap_or(&0),
+            FilterOperation::ReadVariable(name) => *self.values.get(name).unwrap_or(&0)
+        })
+    }
+}
+
+/// What sort of mistake stopped the story.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// Play was sent to a scene or line that doesn't exist.
+    MissingScene,
+    /// A division by zero or a number too large to hold.
+    Arithmetic,
+    /// Play goes round these scenes forever without showing anything.
+    Loop(Vec<String>),
+    /// Play passed through the step limit's worth of lines without showing anything.
+    StepLimit,
+    /// Too many `*call`s were waiting to return.
+    CallDepth,
+}
+
+/// Something in a zone that stopped the story, such as a link to a scene
+/// that doesn't exist or a division by zero, and where it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoryError {
+    pub kind: ErrorKind,
+    pub scene: String,
+    pub file: String,
+    pub line: usize,
+    pub cause: String,
+}
+
+impl fmt::Display for StoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}, line {}, ", self.file, self.line)?;
+        }
+        write!(f, "scene '{}': {}", self.scene, self.cause)
+    }
+}
+
+impl std::error::Error for StoryError {}
+
+/// A piece of a visible line, with all story logic already applied.
+#[derive(Debug, Clone)]
+pub enum Fragment {
+    Text(String),
+    Link(TextLink),
+    /// A link that can't be taken yet, with the reason to show the player.
+    Locked(TextLink, String),
+}
+
+/// Everything a front end needs to draw the lines currently waiting on the player.
+#[derive(Debug, Clone)]
+pub struct View {
+    pub branch: bool,
+    pub lines: Vec<Vec<Fragment>>,
+}
+
+/// How many lines play may pass through looking for one to show before the
+/// story is stopped, unless changed with `Engine::set_step_limit`.
+pub const DEFAULT_STEP_LIMIT: usize = 100_000;
+
+// How many `*call`s may be waiting to return at once
+const MAX_CALL_DEPTH: usize = 100;
+
+// Where play goes after a command
+enum Flow {
+    // On to the following line
+    Continue,
+    // Somewhere else, the line play is now on still has to be looked at
+    Moved,
+    // Nowhere, the story has stopped
+    Stop,
+}
+
+// The scenes play passes through while looking for a line to show, to catch
+// it going round the same ones forever
+#[derive(Default)]
+struct Passage {
+    scenes: Vec<String>,
+    seen: HashMap<u64, usize>,
+}
+
+impl Passage {
+    fn arrive(&mut self, state: &State, zone: &Zone) -> Result<(), Vec<String>> {
+        // Arriving in the same state twice means nothing will ever change
+        let key = state.fingerprint(zone);
+        if let Some(&start) = self.seen.get(&key) {
+            let mut path = self.scenes[start..].to_vec();
+            path.push(state.scene.clone());
+            // Coming back from a call arrives part way through a scene already in the path
+            path.dedup();
+            return Err(path);
+        }
+        self.seen.insert(key, self.scenes.len());
+        self.scenes.push(state.scene.clone());
+        Ok(())
+    }
+}
+
+/// Runs a story independently of how it is presented.
+pub struct Engine {
+    zone: Rc<Zone>,
+    state: State,
+    step_limit: usize,
+    // Why the story couldn't get as far as its first line
+    broken: Option<StoryError>,
+}
+
+impl Engine {
+    pub fn new(zone: Rc<Zone>) -> Self {
+        let state = State::new(&zone);
+        Self::with_state(zone, state)
+    }
+
+    /// Pick up a playthrough, starting it first if it is fresh.
+    pub fn with_state(zone: Rc<Zone>, state: State) -> Self {
+        let mut engine = Self { zone, state, step_limit: DEFAULT_STEP_LIMIT, broken: None };
+        engine.start();
+        engine
+    }
+
+    /// Change how many lines a single step or choice may pass through, such
+    /// as commands and hidden lines, before giving up on finding one to show.
+    pub fn set_step_limit(&mut self, limit: usize) {
+        self.step_limit = limit;
+    }
+
+    pub fn zone(&self) -> &Rc<Zone> {
+        &self.zone
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Throw away all progress and start the zone over.
+    pub fn restart(&mut self) {
+        self.state = State::new(&self.zone);
+        self.start();
+    }
+
+    // Run the header and any commands before the first line to show. A story
+    // that breaks before then can only be restarted, `view` gives the error.
+    fn start(&mut self) {
+        self.broken = None;
+        if !self.state.fresh {
+            return;
+        }
+        let zone = self.zone.clone();
+        let result = self.attempt(|engine| {
+            engine.state.fresh = false;
+            for command in &zone.initialize {
+                if let Command::Set(cmd) = command {
+                    engine.state.apply_set(cmd).map_err(|cause| engine.header_error(cause))?;
+                }
+            }
+            engine.advance_line(false)
+        });
+        if let Err(error) = result {
+            self.broken = Some(error);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.status == Status::Running
+    }
+
+    pub fn current_scene(&self) -> Result<&Scene, StoryError> {
+        self.zone.scene(&self.state.scene).ok_or_else(|| self.missing_scene())
+    }
+
+    fn missing_scene(&self) -> StoryError {
+        self.error(ErrorKind::MissingScene, format!("there is no scene '{}'", self.state.scene))
+    }
+
+    fn looped(&self, path: Vec<String>, without: &str) -> StoryError {
+        let scenes: Vec<String> = path.iter().map(|scene| format!("'{}'", scene)).collect();
+        let cause = format!("play goes round {} forever without {}", scenes.join(" -> "), without);
+        self.error(ErrorKind::Loop(path), cause)
+    }
+
+    // The header has no lines of its own, it sits at the top of the root file
+    fn header_error(&self, cause: String) -> StoryError {
+        StoryError {
+            kind: ErrorKind::Arithmetic,
+            scene: self.state.scene.clone(),
+            file: self.zone.scenes().first().map(|scene| scene.file.clone()).unwrap_or_default(),
+            line: 1,
+            cause: format!("in the header, {}", cause),
+        }
+    }
+
+    // An error at the line play is on
+    fn error(&self, kind: ErrorKind, cause: String) -> StoryError {
+        let (file, line) = self.zone.locate(&self.state.scene, self.state.line).unwrap_or_default();
+        StoryError {
+            kind,
+            scene: self.state.scene.clone(),
+            file,
+            line,
+            cause,
+        }
+    }
+
+    /// The lines currently offered to the player. In a branch scene that is
+    /// every visible choice, otherwise just the line being read.
+    pub fn view(&self) -> Result<View, StoryError> {
+        if let Some(error) = &self.broken {
+            return Err(error.clone());
+        }
+        let scene = self.current_scene()?;
+        let mut lines = Vec::new();
+        if scene.branch {
+            for line in self.branch_lines(scene)? {
+                lines.extend(self.render_active(line)?);
+            }
+        } else if let Some(line) = scene.lines.get(self.state.line) {
+            lines.extend(self.render_active(line)?);
+        }
+        Ok(View {
+            branch: scene.branch,
+            lines,
+        })
+    }
+
+    /// Move the current line into the log and continue to the next one.
+    /// Returns false if the story is not waiting on a plain line.
+    ///
+    /// If the story breaks along the way nothing changes and the error is
+    /// returned, leaving play at the last good state.
+    pub fn step(&mut self) -> Result<bool, StoryError> {
+        if let Some(error) = &self.broken {
+            return Err(error.clone());
+        }
+        if !self.is_running() || self.current_scene()?.branch {
+            return Ok(false);
+        }
+        self.attempt(|engine| {
+            engine.publish_current()?;
+            engine.advance_line(true)
+        })?;
+        Ok(true)
+    }
+
+    /// Read on past lines that offer nothing to choose, the way a player
+    /// clicking along would, until the story stops or offers a choice. Coming
+    /// back to a state already read past means it never will, and is an error.
+    pub fn settle(&mut self) -> Result<(), StoryError> {
+        let mut passed = HashSet::new();
+        let mut scenes: Vec<String> = Vec::new();
+        for _ in 0..self.step_limit {
+            let view = self.view()?;
+            let offers_choice = view.lines.iter().flatten().any(|fragment| !matches!(fragment, Fragment::Text(_)));
+            if !self.is_running() || view.branch || offers_choice {
+                return Ok(());
+            }
+
+            let scene = &self.state.scene;
+            if scenes.last() != Some(scene) {
+                scenes.push(scene.clone());
+            }
+            if !passed.insert(self.state.fingerprint(&self.zone)) {
+                let before = &scenes[..scenes.len() - 1];
+                let start = before.iter().position(|label| label == scene).unwrap_or(before.len());
+                let mut path = scenes[start..].to_vec();
+                if path.len() == 1 { path.push(scene.clone()); }
+                return Err(self.looped(path, "offering a choice"));
+            }
+            if !self.step()? {
+                return Ok(());
+            }
+        }
+        Err(self.error(ErrorKind::StepLimit, format!("play went through {} lines without offering a choice", self.step_limit)))
+    }
+
+    /// Follow a link the player selected from the current view. Returns
+    /// false if the view doesn't offer it, such as a locked or hidden choice.
+    /// Like `step`, nothing changes if the story breaks along the way.
+    pub fn choose(&mut self, link: &TextLink) -> Result<bool, StoryError> {
+        if !self.is_running() {
+            return Ok(false);
+        }
+        let offered = self.view()?.lines.iter().flatten().any(|fragment| match fragment {
+            Fragment::Link(offer) => offer == link,
+            _ => false,
+        });
+        if !offered {
+            return Ok(false);
+        }
+        self.attempt(|engine| {
+            if link.kind == LinkKind::Once {
+                engine.state.taken.insert(engine.link_key(link));
+            }
+            engine.state.coverage.link(&engine.state.scene, &link.destination);
+            engine.publish_link(link)?;
+            engine.follow_link(&link.destination)
+        })?;
+        Ok(true)
+    }
+
+    // Run an action, going back to how things were if it fails or leaves
+    // play somewhere that can't be shown
+    fn attempt(&mut self, action: impl FnOnce(&mut Self) -> Result<(), StoryError>) -> Result<(), StoryError> {
+        // The log only grows, so it is cut back instead of copied every move
+        let log = std::mem::take(&mut self.state.log);
+        let logged = log.len();
+        let last_good = self.state.clone();
+        self.state.log = log;
+
+        let result = action(self).and_then(|_| self.view().map(|_| ()));
+        if result.is_err() {
+            let mut log = std::mem::replace(&mut self.state, last_good).log;
+            log.truncate(logged);
+            self.state.log = log;
+        }
+        result
+    }
+
+    // Lines of a branch scene that aren't skipped over by a jump
+    fn branch_lines<'a>(&self, scene: &'a Scene) -> Result<Vec<&'a Line>, StoryError> {
+        let mut lines = Vec::new();
+        let mut index = 0;
+        while let Some(line) = scene.lines.get(index) {
+            index = match line {
+                Line::Jump(jump) if self.jump_taken(jump)? => jump.target,
+                line => {
+                    lines.push(line);
+                    index + 1
+                },
+            };
+        }
+        Ok(lines)
+    }
+
+    fn jump_taken(&self, jump: &Jump) -> Result<bool, StoryError> {
+        match &jump.unless {
+            Some(filter) => Ok(!self.check_filter(filter)?),
+            None => Ok(true),
+        }
+    }
+
+    fn render_active(&self, line: &Line) -> Result<Option<Vec<Fragment>>, StoryError> {
+        match line {
+            Line::TextLine(line) => self.render_text_line(line),
+            Line::CommandLine(_) | Line::Jump(_) => Ok(None),
+        }
+    }
+
+    fn render_text_line(&self, line: &TextLine) -> Result<Option<Vec<Fragment>>, StoryError> {
+        let mut random = self.state.random.clone();
+        let mut locked = None;
+        if let Some(filter) = &line.filter {
+            if self.state.evaluate_with(&filter.operation, &mut random).map_err(|cause| self.error(ErrorKind::Arithmetic, cause))? == 0 {
+                match &filter.locked {
+                    Some(reason) => locked = Some(reason),
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        // A line offering only links that have been used up disappears entirely
+        let mut available = Vec::new();
+        for part in &line.parts {
+            if let TextPart::Link(link) = part {
+                available.push(self.link_available(link)?);
+            }
+        }
+        if !available.is_empty() && !available.contains(&true) {
+            return Ok(None);
+        }
+
+        let mut available = available.into_iter();
+        let mut fragments = Vec::new();
+        for part in &line.parts {
+            match part {
+                TextPart::Link(link) => if available.next() == Some(true) {
+                    fragments.push(match locked {
+                        Some(reason) => Fragment::Locked(link.clone(), reason.clone()),
+                        None => Fragment::Link(link.clone()),
+                    });
+                },
+                TextPart::Text(text) => fragments.push(Fragment::Text(text.clone())),
+                TextPart::Interpolation(value) => fragments.push(Fragment::Text(self.interpolate(value, &mut random)?)),
+            }
+        }
+        Ok(Some(fragments))
+    }
+
+    // Once links are told apart by the line offering them, so two leading to
+    // the same scene are used up separately
+    fn link_key(&self, link: &TextLink) -> String {
+        format!("{}:{}>{}", self.state.scene, link.line, link.destination)
+    }
+
+    fn link_available(&self, link: &TextLink) -> Result<bool, StoryError> {
+        Ok(match link.kind {
+            LinkKind::Sticky => true,
+            LinkKind::Once => !self.state.taken.contains(&self.link_key(link)),
+            LinkKind::Fallback => !self.once_links_remain()?,
+        })
+    }
+
+    // Whether the current scene still shows any once-only link that hasn't been taken
+    fn once_links_remain(&self) -> Result<bool, StoryError> {
+        for line in self.branch_lines(self.current_scene()?)? {
+            if let Line::TextLine(text) = line {
+                let shown = match &text.filter {
+                    Some(filter) => self.check_filter(filter)?,
+                    None => true,
+                };
+                let once = text.parts.iter().any(|part| match part {
+                    TextPart::Link(link) => link.kind == LinkKind::Once && !self.state.taken.contains(&self.link_key(link)),
+                    _ => false,
+                });
+                if shown && once {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // Each line draws its random numbers from its own copy of the sequence,
+    // so a line reads the same while shown as it does once it is in the log.
+    fn interpolate(&self, value: &Interpolation, random: &mut Random) -> Result<String, StoryError> {
+        Ok(match value {
+            Interpolation::Value(op) => self.state.evaluate_with(op, random).map_err(|cause| self.error(ErrorKind::Arithmetic, cause))?.to_string(),
+            Interpolation::Item(key, field) => {
+                let item = self.state.items.get(key);
+                match field {
+                    ItemField::Name => item.and_then(|item| item.name.clone()).unwrap_or_else(|| key.clone()),
+                    ItemField::Details => item.and_then(|item| item.details.clone()).unwrap_or_default(),
+                }
+            },
+        })
+    }
+
+    // Check a filter on the current line, noting the outcome for coverage
+    fn record_filter(&mut self, filter: &LineFilter) -> Result<bool, StoryError> {
+        let outcome = self.check_filter(filter)?;
+        let scene = self.state.scene.clone();
+        self.state.coverage.filter(&scene, self.state.line, outcome);
+        Ok(outcome)
+    }
+
+    pub fn check_filter(&self, filter: &LineFilter) -> Result<bool, StoryError> {
+        Ok(self.eval_filter(&filter.operation)? != 0)
+    }
+
+    pub fn eval_filter(&self, op: &FilterOperation) -> Result<i32, StoryError> {
+        self.state.evaluate(op).map_err(|cause| self.error(ErrorKind::Arithmetic, cause))
+    }
+
+    fn publish_link(&mut self, link: &TextLink) -> Result<(), StoryError> {
+        let scene = self.current_scene()?.clone();
+        if scene.branch {
+            self.record_branch(&scene)?;
+            for line in self.branch_lines(&scene)? {
+                if let Line::TextLine(text) = line {
+                    if text.include_in_summary {
+                        let line = self.render_inactive(line)?;
+                        self.state.log.push_back(line);
+                    }
+                }
+            }
+        } else {
+            self.state.coverage.hit(&scene.label, self.state.line);
+        }
+        self.state.log.push_back(self.render_inactive_link(&link.text));
+        Ok(())
+    }
+
+    // Taking a choice in a branch scene means everything it showed has been read
+    fn record_branch(&mut self, scene: &Scene) -> Result<(), StoryError> {
+        let mut index = 0;
+        while let Some(line) = scene.lines.get(index) {
+            index = match line {
+                Line::Jump(jump) => {
+                    let taken = self.jump_taken(jump)?;
+                    if jump.unless.is_some() {
+                        self.state.coverage.filter(&scene.label, index, !taken);
+                    }
+                    if taken { jump.target } else { index + 1 }
+                },
+                Line::TextLine(text) => {
+                    let shown = match &text.filter {
+                        Some(filter) => {
+                            let outcome = self.check_filter(filter)?;
+                            self.state.coverage.filter(&scene.label, index, outcome);
+                            outcome || filter.locked.is_some()
+                        },
+                        None => true,
+                    };
+                    if shown {
+                        self.state.coverage.hit(&scene.label, index);
+                    }
+                    index + 1
+                },
+                Line::CommandLine(_) => index + 1,
+            };
+        }
+        Ok(())
+    }
+
+    fn render_inactive_link(&self, text: &str) -> String {
+        String::from("<span class=\"inline-disabled-button\">") + text + "</span>"
+    }
+
+    fn render_inactive(&self, line: &Line) -> Result<String, StoryError> {
+        match line {
+            Line::TextLine(textline) => {
+                let mut random = self.state.random.clone();
+                if let Some(filter) = &textline.filter {
+                    self.state.evaluate_with(&filter.operation, &mut random).map_err(|cause| self.error(ErrorKind::Arithmetic, cause))?;
+                }
+                let mut buffer = String::from("");
+                for part in textline.parts.iter() {
+                    match part {
+                        TextPart::Link(l) => buffer += &self.render_inactive_link(&l.text),
+                        TextPart::Text(t) => buffer += t,
+                        TextPart::Interpolation(value) => buffer += &self.interpolate(value, &mut random)?,
+                    }
+                }
+                Ok(buffer)
+            },
+            Line::CommandLine(_) | Line::Jump(_) => Ok(String::from("")),
+        }
+    }
+
+    fn publish_current(&mut self) -> Result<(), StoryError> {
+        let line = {
+            let scene = self.current_scene()?;
+            if scene.branch {
+                return Ok(());
+            }
+            match scene.lines.get(self.state.line) {
+                Some(line) => self.render_inactive(line)?,
+                None => return Err(self.error(ErrorKind::MissingScene, format!("there is no line {} in the scene", self.state.line + 1))),
+            }
+        };
+        let scene = self.state.scene.clone();
+        self.state.coverage.hit(&scene, self.state.line);
+        if !line.is_empty() {
+            self.state.log.push_back(line);
+        }
+        Ok(())
+    }
+
+    // Carry on from the current line until there is one to show or the story
+    // stops. Commands that move play elsewhere are followed here in a loop,
+    // so a long run of them can't use up the stack.
+    fn advance_line(&mut self, inc: bool) -> Result<(), StoryError> {
+        if inc {
+            self.state.line += 1;
+        }
+        let zone = self.zone.clone();
+        let mut passage = Passage::default();
+        passage.arrive(&self.state, &zone).map_err(|path| self.looped(path, "showing anything"))?;
+
+        for _ in 0..self.step_limit {
+            let scene = zone.scene(&self.state.scene).ok_or_else(|| self.missing_scene())?;
+            if scene.branch {
+                return Ok(());
+            }
+
+            // Reached the end
+            let line = match scene.lines.get(self.state.line) {
+                Some(line) => line,
+                None => {
+                    self.advance_scene()?;
+                    passage.arrive(&self.state, &zone).map_err(|path| self.looped(path, "showing anything"))?;
+                    continue;
+                },
+            };
+
+            match line {
+                Line::TextLine(line) => {
+                    // Skip lines that are filtered
+                    if let Some(filter) = &line.filter {
+                        if !self.record_filter(filter)? {
+                            self.state.line += 1;
+                            continue;
+                        }
+                    }
+                    return Ok(());
+                },
+                Line::CommandLine(line) => {
+                    // Skip commands that are filtered, otherwise execute them
+                    if let Some(filter) = &line.filter {
+                        if !self.record_filter(filter)? {
+                            self.state.line += 1;
+                            continue;
+                        }
+                    }
+                    self.state.coverage.hit(&scene.label, self.state.line);
+                    match self.execute_command(&line.command)? {
+                        Flow::Continue => self.state.line += 1,
+                        Flow::Moved => passage.arrive(&self.state, &zone).map_err(|path| self.looped(path, "showing anything"))?,
+                        Flow::Stop => return Ok(()),
+                    }
+                },
+                Line::Jump(jump) => {
+                    let taken = match &jump.unless {
+                        Some(filter) => !self.record_filter(filter)?,
+                        None => true,
+                    };
+                    if taken {
+                        self.state.line = jump.target;
+                    } else {
+                        self.state.line += 1;
+                    }
+                },
+            }
+        }
+        Err(self.error(ErrorKind::StepLimit, format!("play went through {} lines without finding one to show", self.step_limit)))
+    }
+
+    fn follow_link(&mut self, link: &str) -> Result<(), StoryError> {
+        self.enter(link)?;
+        self.advance_line(false)
+    }
+
+    // Move to the start of a scene without reading any of it
+    fn enter(&mut self, link: &str) -> Result<(), StoryError> {
+        if self.zone.scene(link).is_none() {
+            return Err(self.error(ErrorKind::MissingScene, format!("there is no scene '{}'", link)));
+        }
+        self.state.line = 0;
+        self.state.scene = link.to_string();
+        self.state.visits.insert(self.state.scene.clone(), 1 + self.count_visits(&self.state.scene));
+        Ok(())
+    }
+
+    fn advance_scene(&mut self) -> Result<(), StoryError> {
+        let next = self.zone.following(&self.state.scene)
+            .ok_or_else(|| self.error(ErrorKind::MissingScene, String::from("the scene runs past the end of the zone")))?;
+        self.enter(&next)
+    }
+
+    fn execute_command(&mut self, command: &Command) -> Result<Flow, StoryError> {
+        match command {
+            Command::Item(items) => {
+                for (key, value) in items.change.iter() {
+                    // Removing more than is held empties the slot rather than going negative
+                    let count = self.count_item(key).saturating_add(*value).max(0);
+                    if count == 0 {
+                        self.state.inventory.remove(key);
+                    } else {
+                        self.state.inventory.insert(key.clone(), count);
+                    }
+                }
+                Ok(Flow::Continue)
+            },
+            Command::Next(link) => {
+                self.enter(link)?;
+                Ok(Flow::Moved)
+            },
+            Command::Call(link) => {
+                if self.state.stack.len() >= MAX_CALL_DEPTH {
+                    return Err(self.error(ErrorKind::CallDepth, format!("more than {} calls are waiting to return", MAX_CALL_DEPTH)));
+                }
+                self.state.stack.push(ReturnPoint {
+                    scene: self.state.scene.clone(),
+                    line: self.state.line + 1,
+                });
+                self.enter(link)?;
+                Ok(Flow::Moved)
+            },
+            Command::Return => {
+                match self.state.stack.pop() {
+                    Some(point) => {
+                        self.state.scene = point.scene;
+                        self.state.line = point.line;
+                    },
+                    // Nothing to return to, carry on as if the scene had ended
+                    None => self.advance_scene()?,
+                }
+                Ok(Flow::Moved)
+            },
+            Command::End => {
+                self.state.status = Status::Finished;
+                Ok(Flow::Stop)
+            },
+            Command::Reset => {
+                self.state.status = Status::Reset;
+                Ok(Flow::Stop)
+            },
+            Command::Set(cmd) => {
+                self.state.apply_set(cmd).map_err(|cause| self.error(ErrorKind::Arithmetic, cause))?;
+                Ok(Flow::Continue)
+            },
+            Command::Roll(cmd) => {
+                self.state.apply_roll(cmd);
+                Ok(Flow::Continue)
+            },
+            Command::SetItem(item) => {
+                match self.state.items.get_mut(&item.key) {
+                    Some(val) => {
+                        val.update(item);
+                    },
+                    None => {
+                        self.state.items.insert(item.key.clone(), item.clone());
+                    },
+                }
+                Ok(Flow::Continue)
+            },
+        }
+    }
+
+    fn count_item(&self, name: &str) -> i32 {
+        match self.state.inventory.get(name) {
+            Some(value) => *value,
+            None => 0,
+        }
+    }
+
+    fn count_visits(&self, name: &str) -> u32 {
+        match self.state.visits.get(name) {
+            Some(value) => *value,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::build_world;
+
+    fn engine(source: &str) -> Engine {
+        let zone = build_world(source.to_string()).unwrap();
+        let state = State::with_seed(&zone, 1);
+        Engine::with_state(zone, state)
+    }
+
+    // The words of the line waiting on the player
+    fn showing(engine: &Engine) -> String {
+        engine.view().unwrap().lines.iter().flatten().map(|fragment| match fragment {
+            Fragment::Text(text) => text.as_str(),
+            Fragment::Link(link) | Fragment::Locked(link, _) => link.text.as_str(),
+        }).collect()
+    }
+
+    fn link(engine: &Engine, destination: &str) -> TextLink {
+        engine.view().unwrap().lines.iter().flatten().find_map(|fragment| match fragment {
+            Fragment::Link(link) | Fragment::Locked(link, _) if link.destination == destination => Some(link.clone()),
+            _ => None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn commands_before_the_first_line_run() {
+        let engine = engine("default:\n    *set x = 5\n    *item +3 coins\n    x is {x}\n    *end\n");
+        assert_eq!(showing(&engine), "x is 5");
+        assert_eq!(engine.state().inventory.get("coins"), Some(&3));
+    }
+
+    #[test]
+    fn hidden_first_line_is_skipped() {
+        let mut engine = engine("default:\n    (1 == 0) hidden\n    shown\n    *end\n");
+        assert_eq!(showing(&engine), "shown");
+        assert!(engine.step().unwrap());
+        assert_eq!(engine.state().log, vec![String::from("shown")]);
+        assert_eq!(engine.state().status, Status::Finished);
+    }
+
+    #[test]
+    fn restart_starts_at_the_first_line() {
+        let mut engine = engine("default:\n    *set x = x + 1\n    x is {x}\n    *reset\n");
+        engine.step().unwrap();
+        assert_eq!(engine.state().status, Status::Reset);
+        engine.restart();
+        assert_eq!(showing(&engine), "x is 1");
+        assert!(engine.state().log.is_empty());
+    }
+
+    #[test]
+    fn saved_state_is_not_started_again() {
+        let first = engine("default:\n    *set x = x + 1\n    x is {x}\n    *end\n");
+        let again = Engine::with_state(first.zone().clone(), first.state().clone());
+        assert_eq!(showing(&again), "x is 1");
+        assert_eq!(again.state().values.get("x"), Some(&1));
+    }
+
+    #[test]
+    fn play_begins_in_the_start_scene() {
+        let engine = engine("*start river\n---\ndefault:\n    Not here.\n    *end\n\nriver:\n    Here.\n    *end\n");
+        assert_eq!(engine.state().scene, "river");
+        assert_eq!(showing(&engine), "Here.");
+    }
+
+    #[test]
+    fn choosing_a_link_follows_it() {
+        let mut engine = engine("default: ??\n    ? Where to?\n    [left | Go left.]\n    [right | Go right.]\n\n    left:\n        Went left.\n        *end\n\n    right:\n        Went right.\n        *end\n");
+        let right = link(&engine, "default.right");
+        assert!(engine.choose(&right).unwrap());
+        assert_eq!(engine.state().scene, "default.right");
+        assert_eq!(showing(&engine), "Went right.");
+    }
+
+    #[test]
+    fn failing_filters_with_a_reason_lock_their_links() {
+        let mut locked = engine("default: ??\n    ? Read?\n    ($letter | Requires: letter) [decode | Decode it.]\n    ($key | Requires: key) [open | Open it.]\n    ($key) [hidden | Hidden.]\n\ndecode:\n    *end\n\nopen:\n    *end\n\nhidden:\n    *end\n");
+        let fragments: Vec<Fragment> = locked.view().unwrap().lines.into_iter().flatten().collect();
+        assert!(fragments.iter().any(|fragment| matches!(fragment, Fragment::Locked(link, reason) if link.destination == "decode" && reason == "Requires: letter")));
+        assert!(!fragments.iter().any(|fragment| matches!(fragment, Fragment::Link(link) | Fragment::Locked(link, _) if link.destination == "hidden")));
+        assert!(!locked.choose(&link(&locked, "decode")).unwrap());
+
+        let mut open = engine("default:\n    *item +1 letter\n    *next read\n\nread: ??\n    ? Read?\n    ($letter | Requires: letter) [decode | Decode it.]\n\ndecode:\n    Decoded.\n    *end\n");
+        let decode = link(&open, "decode");
+        assert!(open.view().unwrap().lines.iter().flatten().any(|fragment| matches!(fragment, Fragment::Link(link) if link == &decode)));
+        assert!(open.choose(&decode).unwrap());
+        assert_eq!(showing(&open), "Decoded.");
+    }
+
+    #[test]
+    fn once_links_go_and_fallbacks_arrive() {
+        let mut engine = engine("default: ??\n    ? Ask?\n    [*news | Any news?]\n    [>bye | Bye.]\n\n    news:\n        None.\n        *next default\n\n    bye:\n        Bye.\n        *end\n");
+        assert_eq!(showing(&engine), "Ask?Any news?");
+        let news = link(&engine, "default.news");
+        engine.choose(&news).unwrap();
+        engine.step().unwrap();
+        assert_eq!(showing(&engine), "Ask?Bye.");
+        assert!(!engine.choose(&news).unwrap());
+    }
+
+    #[test]
+    fn once_links_to_the_same_scene_are_used_up_apart() {
+        let mut engine = engine("default: ??\n    ? Ask?\n    [*talk | About the weather.]\n    [*talk | About the river.]\n    [>bye | Bye.]\n\ntalk:\n    Talk.\n    *next default\n\nbye:\n    *end\n");
+        let weather = link(&engine, "talk");
+        engine.choose(&weather).unwrap();
+        engine.step().unwrap();
+        assert_eq!(showing(&engine), "Ask?About the river.");
+        assert!(!engine.choose(&weather).unwrap());
+    }
+
+    #[test]
+    fn items_are_removed_down_to_zero() {
+        let mut engine = engine("default:\n    *item +3 coins\n    Have {$coins}.\n    *item -2 coins\n    Now {$coins}.\n    *item -5 coins\n    Then {$coins}.\n    *end\n");
+        assert_eq!(showing(&engine), "Have 3.");
+        engine.step().unwrap();
+        assert_eq!(showing(&engine), "Now 1.");
+        engine.step().unwrap();
+        assert_eq!(showing(&engine), "Then 0.");
+        assert_eq!(engine.state().inventory.get("coins"), None);
+    }
+
+    #[test]
+    fn if_blocks_take_one_branch() {
+        let zone = "default:\n    *if x == 1:\n        One.\n    *elif x == 2:\n        Two.\n    *else:\n        Other.\n    After.\n    *end\n";
+        for (x, shown) in [(1, "One."), (2, "Two."), (3, "Other.")] {
+            let mut engine = engine(&format!("*set x = {}\n---\n{}", x, zone));
+            assert_eq!(showing(&engine), shown);
+            engine.step().unwrap();
+            assert_eq!(showing(&engine), "After.");
+        }
+    }
+
+    #[test]
+    fn if_blocks_nest() {
+        let zone = "default:\n    *if x > 0:\n        *if x > 5:\n            Big.\n        *else:\n            Small.\n        Positive.\n    *else:\n        Nothing.\n    *end\n";
+        let mut big = engine(&format!("*set x = 9\n---\n{}", zone));
+        assert_eq!(showing(&big), "Big.");
+        big.step().unwrap();
+        assert_eq!(showing(&big), "Positive.");
+        let small = engine(&format!("*set x = 2\n---\n{}", zone));
+        assert_eq!(showing(&small), "Small.");
+        let nothing = engine(zone);
+        assert_eq!(showing(&nothing), "Nothing.");
+    }
+
+    #[test]
+    fn if_blocks_choose_the_links_of_a_branch_scene() {
+        let zone = "default: ??\n    ? Where?\n    *if key:\n        [door | Open the door.]\n    *else:\n        [wait | Wait.]\n    [leave | Leave.]\n\ndoor:\n    *end\n\nwait:\n    *end\n\nleave:\n    *end\n";
+        assert_eq!(showing(&engine(&format!("*set key = 1\n---\n{}", zone))), "Where?Open the door.Leave.");
+        assert_eq!(showing(&engine(zone)), "Where?Wait.Leave.");
+    }
+
+    #[test]
+    fn header_errors_stop_the_story() {
+        let mut engine = engine("*set x = 1 / 0\n---\ndefault:\n    Hi.\n    *end\n");
+        let error = engine.view().unwrap_err();
+        assert_eq!(error.cause, "in the header, '1 / 0' divides by zero");
+        assert_eq!(engine.step(), Err(error.clone()));
+        engine.restart();
+        assert_eq!(engine.view().unwrap_err(), error);
+    }
+
+    #[test]
+    fn errors_leave_play_where_it_was() {
+        let mut engine = engine("default:\n    Before.\n    *set x = 10 / x\n    After.\n    *end\n");
+        let before = engine.state().clone();
+        let error = engine.step().unwrap_err();
+        assert_eq!(error.cause, "'10 / x' divides by zero");
+        assert_eq!(error.line, 3);
+        assert_eq!(engine.state().log, before.log);
+        assert_eq!(showing(&engine), "Before.");
+    }
+
+    #[test]
+    fn silent_loops_are_reported() {
+        let mut engine = engine("default: ??\n    ? Go?\n    [a | Go.]\n\na:\n    *set x = 1\n    *next b\n\nb:\n    *next a\n");
+        let go = link(&engine, "a");
+        let error = engine.choose(&go).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Loop(vec![String::from("b"), String::from("a"), String::from("b")]));
+        assert_eq!(engine.state().scene, "default");
+    }
+
+    #[test]
+    fn settling_reads_on_to_a_choice() {
+        let mut reader = engine("default:\n    One.\n    Two.\n    *next pick\n\npick: ??\n    ? Pick.\n    [pick | Again.]\n");
+        reader.settle().unwrap();
+        assert_eq!(reader.state().log.iter().collect::<Vec<_>>(), vec!["One.", "Two."]);
+        assert_eq!(showing(&reader), "Pick.Again.");
+
+        let mut circler = engine("default:\n    Round.\n    *next again\n\nagain:\n    And round.\n    *next default\n");
+        let error = circler.settle().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Loop(vec![String::from("default"), String::from("again"), String::from("default")]));
+        assert_eq!(error.cause, "play goes round 'default' -> 'again' -> 'default' forever without offering a choice");
+    }
+
+    #[test]
+    fn long_runs_of_commands_use_the_step_limit() {
+        let mut engine = engine("default:\n    Start.\n    *next count\n\ncount:\n    *set n = n + 1\n    (n < 100) *next count\n    Done {n}.\n    *end\n");
+        engine.set_step_limit(50);
+        assert_eq!(engine.step().unwrap_err().kind, ErrorKind::StepLimit);
+        engine.set_step_limit(DEFAULT_STEP_LIMIT);
+        assert!(engine.step().unwrap());
+    }
+
+    #[test]
+    fn return_resumes_after_the_call() {
+        let mut engine = engine("default:\n    Before.\n    *call greet\n    After.\n    *end\n\ngreet:\n    Hello.\n    *return\n");
+        engine.step().unwrap();
+        assert_eq!((engine.state().scene.as_str(), showing(&engine)), ("greet", String::from("Hello.")));
+        engine.step().unwrap();
+        assert_eq!((engine.state().scene.as_str(), showing(&engine)), ("default", String::from("After.")));
+        assert!(engine.state().stack.is_empty());
+    }
+
+    #[test]
+    fn return_without_a_call_goes_on_to_the_next_scene() {
+        let mut engine = engine("default:\n    Start.\n    *return\n\nnext:\n    Next.\n    *end\n");
+        engine.step().unwrap();
+        assert_eq!((engine.state().scene.as_str(), showing(&engine)), ("next", String::from("Next.")));
+    }
+
+    #[test]
+    fn calls_are_limited_in_depth() {
+        let mut engine = engine("default:\n    Start.\n    *call deeper\n    *end\n\ndeeper:\n    *call deeper\n    *return\n");
+        assert_eq!(engine.step().unwrap_err().kind, ErrorKind::CallDepth);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use crate::engine::{Engine, ErrorKind, Fragment, Random, State, Status, StoryError};
+use crate::zone::{write_location, TextLink, Zone};
+
+/// How far to look before giving up on a branch of the story.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The most choices to make in a single playthrough.
+    pub depth: usize,
+    /// The most distinct states to visit in total.
+    pub states: usize,
+    /// Where the random rolls come from. Only this one sequence of rolls is
+    /// explored, other seeds can lead play elsewhere.
+    pub seed: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { depth: 50, states: 100_000, seed: 0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The story stopped with `*end` or `*reset`.
+    Ending(Status),
+    /// A branch scene where every choice is hidden or locked.
+    Softlock,
+    /// Play goes round the listed scenes forever without offering a choice.
+    Loop(Vec<String>),
+    /// The zone broke the story, such as by dividing by zero.
+    Error(StoryError),
+    /// The runtime panicked with this message.
+    Panic(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Ending(Status::Finished) => write!(f, "ending: the story ends"),
+            Outcome::Ending(Status::Reset) => write!(f, "ending: the story starts over"),
+            Outcome::Ending(Status::Running) => write!(f, "ending: the story stops"),
+            Outcome::Softlock => write!(f, "softlock: every choice is hidden or locked"),
+            Outcome::Loop(path) => write!(f, "loop: {} never stops for a choice", path.iter().map(|scene| format!("'{}'", scene)).collect::<Vec<_>>().join(" -> ")),
+            Outcome::Error(error) => write!(f, "error: {}", error.cause),
+            Outcome::Panic(message) => write!(f, "panic: {}", message),
+        }
+    }
+}
+
+/// Something reachable in a zone, along with the shortest list of choices
+/// that gets there. Choices are written the way a test script takes them.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub outcome: Outcome,
+    pub scene: String,
+    pub file: String,
+    pub line: usize,
+    pub choices: Vec<String>,
+}
+
+impl Finding {
+    /// Endings are what a story is meant to reach, anything else is a problem.
+    pub fn is_problem(&self) -> bool {
+        !matches!(self.outcome, Outcome::Ending(_))
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, &self.file, self.line)?;
+        write!(f, ", scene '{}': {}", self.scene, self.outcome)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// Distinct states visited.
+    pub states: usize,
+    /// States left unexplored because a limit was reached.
+    pub unexplored: usize,
+    /// Whether play rolled any dice, so that another seed could find more.
+    pub rolled: bool,
+}
+
+/// Try every choice sequence reachable from the start of a zone, up to the
+/// given limits, and report where play can end up.
+///
+/// Playthroughs that arrive at the same state are only explored once. Rolls
+/// are not branched on: random numbers come from the seed in the limits, so
+/// the same zone always gives the same report but only one sequence of rolls
+/// is tried.
+///
+/// Panics in the engine are caught and reported as findings. The panic hook
+/// is left as it is, so they are still printed unless the caller silences it.
+pub fn explore(zone: Rc<Zone>, limits: Limits) -> Report {
+    Explorer::new(zone).run(limits)
+}
+
+/// Play a zone once from the start, taking a random choice whenever one is
+/// offered, until the story stops, something goes wrong or `depth` choices
+/// have been made.
+///
+/// Each choice is handed to `record` before it is taken, so the way there is
+/// known even when taking it brings the whole process down.
+pub fn random_walk(zone: Rc<Zone>, seed: u64, depth: usize, mut record: impl FnMut(&str)) -> Option<Finding> {
+    let explorer = Explorer::new(zone.clone());
+    let mut engine = Engine::with_state(zone.clone(), State::with_seed(&zone, seed));
+    let mut random = Random::new(seed);
+    let mut choices = Vec::new();
+    loop {
+        let options = match explorer.settle(&mut engine).and_then(|_| explorer.options(&engine)) {
+            Ok(options) => options,
+            Err(outcome) => return Some(explorer.finding(&engine, outcome, choices)),
+        };
+        if choices.len() >= depth {
+            return None;
+        }
+
+        let choice = &options[random.range(0, options.len() as i32 - 1) as usize];
+        record(&choice.name());
+        choices.push(choice.name());
+        if let Err(outcome) = explorer.take(&mut engine, choice) {
+            return Some(explorer.finding(&engine, outcome, choices));
+        }
+    }
+}
+
+// Something the player can do where play has stopped
+enum Choice {
+    Link(TextLink),
+    Continue,
+}
+
+impl Choice {
+    // Written the way a test script takes it
+    fn name(&self) -> String {
+        match self {
+            Choice::Link(link) => link.destination.clone(),
+            Choice::Continue => String::from("continue"),
+        }
+    }
+}
+
+struct Explorer {
+    zone: Rc<Zone>,
+    seen: HashSet<String>,
+    reported: HashSet<String>,
+    report: Report,
+}
+
+impl Explorer {
+    fn new(zone: Rc<Zone>) -> Self {
+        Self {
+            zone,
+            seen: HashSet::new(),
+            reported: HashSet::new(),
+            report: Report::default(),
+        }
+    }
+
+    fn run(mut self, limits: Limits) -> Report {
+        let mut queue = VecDeque::new();
+        queue.push_back((State::with_seed(&self.zone, limits.seed), Vec::new()));
+
+        while let Some((state, choices)) = queue.pop_front() {
+            let mut engine = Engine::with_state(self.zone.clone(), state);
+            let settled = self.settle(&mut engine);
+            self.report.rolled |= engine.state().random.position > 0;
+            if let Err(outcome) = settled {
+                self.found(&engine, outcome, choices);
+                continue;
+            }
+            if !self.seen.insert(engine.state().key(&self.zone)) {
+                continue;
+            }
+            let options = match self.options(&engine) {
+                Ok(options) => options,
+                Err(outcome) => {
+                    self.found(&engine, outcome, choices);
+                    continue;
+                },
+            };
+            if self.seen.len() > limits.states || choices.len() >= limits.depth {
+                self.report.unexplored += 1;
+                continue;
+            }
+
+            for choice in options {
+                let mut next = Engine::with_state(self.zone.clone(), engine.state().clone());
+                let mut path = choices.clone();
+                path.push(choice.name());
+                match self.take(&mut next, &choice) {
+                    Ok(()) => queue.push_back((next.state().clone(), path)),
+                    Err(outcome) => self.found(&engine, outcome, path),
+                }
+            }
+        }
+
+        self.report.states = self.seen.len();
+        self.report
+    }
+
+    // What the player can do where play has stopped, or how the story ended up there
+    fn options(&self, engine: &Engine) -> Result<Vec<Choice>, Outcome> {
+        if !engine.is_running() {
+            return Err(Outcome::Ending(engine.state().status.clone()));
+        }
+        let view = engine.view().map_err(Outcome::Error)?;
+        let mut options: Vec<Choice> = view.lines.iter().flatten().filter_map(|fragment| match fragment {
+            Fragment::Link(link) => Some(Choice::Link(link.clone())),
+            _ => None,
+        }).collect();
+        if view.branch && options.is_empty() {
+            return Err(Outcome::Softlock);
+        }
+        // A line offering links can also just be read past
+        if !view.branch {
+            options.push(Choice::Continue);
+        }
+        Ok(options)
+    }
+
+    fn take(&self, engine: &mut Engine, choice: &Choice) -> Result<(), Outcome> {
+        match choice {
+            Choice::Link(link) => attempt(|| engine.choose(link)).map_err(Outcome::Panic)?.map(|_| ()).map_err(Outcome::Error),
+            Choice::Continue => self.step(engine),
+        }
+    }
+
+    fn settle(&self, engine: &mut Engine) -> Result<(), Outcome> {
+        attempt(|| engine.settle()).map_err(Outcome::Panic)?.map_err(Outcome::Error)
+    }
+
+    fn step(&self, engine: &mut Engine) -> Result<(), Outcome> {
+        attempt(|| engine.step()).map_err(Outcome::Panic)?.map(|_| ()).map_err(Outcome::Error)
+    }
+
+    fn finding(&self, engine: &Engine, outcome: Outcome, choices: Vec<String>) -> Finding {
+        // An error knows better where it happened than the state play was left in
+        if let Outcome::Error(error) = outcome {
+            // Loops are found by the engine, they are still loops
+            let outcome = match &error.kind {
+                ErrorKind::Loop(path) => Outcome::Loop(path.clone()),
+                _ => Outcome::Error(error.clone()),
+            };
+            return Finding {
+                scene: error.scene,
+                file: error.file,
+                line: error.line,
+                outcome,
+                choices,
+            };
+        }
+        let state = engine.state();
+        let (file, line) = self.zone.locate(&state.scene, state.line).unwrap_or_default();
+        Finding {
+            outcome,
+            scene: state.scene.clone(),
+            file,
+            line,
+            choices,
+        }
+    }
+
+    fn found(&mut self, engine: &Engine, outcome: Outcome, choices: Vec<String>) {
+        // Only the first, and so shortest, way to reach each finding is kept
+        let finding = self.finding(engine, outcome, choices);
+        if self.reported.insert(finding.to_string()) {
+            self.report.findings.push(finding);
+        }
+    }
+}
+
+// Run part of the engine, turning a panic into its message
+fn attempt<T>(action: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(action)).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            String::from("unknown panic")
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::build_world;
+
+    const ZONE: &str = "default: ??
+    ? Start.
+    [hall | Hall.]
+    [door | Door.]
+
+hall: ??
+    ? Hall.
+    [door | Door.]
+    [cellar | Cellar.]
+    [kitchen | Kitchen.]
+    [attic | Attic.]
+
+door:
+    Outside.
+    *end
+
+cellar:
+    *next stairs
+
+stairs:
+    *next cellar
+
+kitchen:
+    *set x = 1 / 0
+    Broken.
+    *end
+
+attic: ??
+    ? Dark.
+    (key) [door | Climb out.]
+";
+
+    fn findings() -> Vec<(String, Vec<String>)> {
+        let report = explore(build_world(ZONE.to_string()).unwrap(), Limits::default());
+        assert_eq!(report.unexplored, 0);
+        assert!(!report.rolled);
+        report.findings.iter().map(|finding| (finding.outcome.to_string(), finding.choices.clone())).collect()
+    }
+
+    fn choices(list: &[&str]) -> Vec<String> {
+        list.iter().map(|choice| choice.to_string()).collect()
+    }
+
+    #[test]
+    fn endings_are_reached_the_shortest_way() {
+        assert!(findings().contains(&(String::from("ending: the story ends"), choices(&["door"]))));
+    }
+
+    #[test]
+    fn branches_with_nothing_to_choose_are_softlocks() {
+        assert!(findings().contains(&(String::from("softlock: every choice is hidden or locked"), choices(&["hall", "attic"]))));
+    }
+
+    #[test]
+    fn loops_are_found_with_their_path() {
+        let loops: Vec<_> = findings().into_iter().filter(|(outcome, _)| outcome.starts_with("loop")).collect();
+        assert_eq!(loops, vec![(String::from("loop: 'cellar' -> 'stairs' -> 'cellar' never stops for a choice"), choices(&["hall", "cellar"]))]);
+    }
+
+    #[test]
+    fn errors_are_found_where_they_happen() {
+        let report = explore(build_world(ZONE.to_string()).unwrap(), Limits::default());
+        let error = report.findings.iter().find(|finding| matches!(finding.outcome, Outcome::Error(_))).unwrap();
+        assert_eq!(error.to_string(), "line 24, scene 'kitchen': error: '1 / 0' divides by zero");
+        assert_eq!(error.choices, choices(&["hall", "kitchen"]));
+        assert_eq!(report.findings.iter().filter(|finding| finding.is_problem()).count(), 3);
+    }
+
+    const DICE: &str = "default: ??
+    ? Which door?
+    [left | Left.]
+    [right | Right.]
+
+left:
+    *roll x = d3
+    *set y = 10 / (x - 1)
+    Made it.
+    *next default
+
+right:
+    *end
+";
+
+    #[test]
+    fn random_walks_find_seeded_errors_with_their_choices() {
+        let walk = |seed| {
+            let mut recorded = Vec::new();
+            let finding = random_walk(build_world(DICE.to_string()).unwrap(), seed, 20, |choice| recorded.push(choice.to_string())).unwrap();
+            assert_eq!(finding.choices, recorded);
+            finding
+        };
+        let finding = walk(9);
+        assert_eq!(finding.to_string(), "line 8, scene 'left': error: '10 / (x - 1)' divides by zero");
+        assert_eq!(finding.choices, choices(&["left", "left", "left"]));
+        assert_eq!(walk(9).choices, finding.choices);
+
+        let finding = walk(6);
+        assert!(!finding.is_problem());
+        assert_eq!(finding.choices, choices(&["left", "right"]));
+    }
+}
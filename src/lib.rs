@@ -0,0 +1,5 @@
+pub mod zone;
+pub mod engine;
+pub mod script;
+pub mod explore;
+pub mod coverage;
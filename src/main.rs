@@ -1,6 +1,5 @@
 
 mod root;
-mod zone;
 mod raw;
 mod display;
 
@@ -9,4 +8,4 @@ use root::Root;
 
 fn main() {
     yew::start_app::<Root>();
-}
\ No newline at end of file
+}
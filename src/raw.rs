@@ -42,7 +42,6 @@ impl Component for Raw {
         span.set_inner_html(&self.props.inner_html[..]);
 
         let node = Node::from(span);
-        let vnode = VNode::VRef(node);
-        vnode
+        VNode::VRef(node)
     }
 }
\ No newline at end of file
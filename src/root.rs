@@ -10,18 +10,22 @@ use url::Url;
 use anyhow::anyhow;
 
 use crate::display::Display;
-use crate::zone::{build_world, Zone};
+use smooth_river_stones::zone::{assemble, Zone, ZoneError, ZoneFile};
+use smooth_river_stones::zone::lint::{lint, Severity};
 
 pub enum Message {
-    ZoneLoad(String),
+    ZoneLoad(String, String),
     ZoneLoadError(anyhow::Error)
 }
 
 pub struct Root {
-    _link: ComponentLink<Self>,
-    _zone_fetch: Option<FetchTask>,
+    link: ComponentLink<Self>,
+    zone_fetches: HashMap<String, FetchTask>,
+    zone_url: String,
+    files: HashMap<String, ZoneFile>,
     session_key: String,
     load_error: Option<anyhow::Error>,
+    zone_error: Option<ZoneError>,
     zone: Option<Rc<Zone>>,
 }
 
@@ -51,7 +55,93 @@ fn build_target_zone() -> Result<(Url, String), anyhow::Error> {
     url.set_fragment(None);
     url = url.join(&path)?;
 
-    return Ok((url, session));
+    Ok((url, session))
+}
+
+fn resolve_include(from: &str, path: &str) -> String {
+    match Url::parse(from).and_then(|base| base.join(path)) {
+        Ok(url) => url.to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+impl Root {
+    fn fetch_zone_file(&mut self, url: String) -> Result<(), anyhow::Error> {
+        ConsoleService::info(&format!("Loading zone at: {}", url));
+
+        let request = Request::get(&url).body(Nothing)?;
+
+        let callback = self.link.callback({
+            let url = url.clone();
+            move |response: Response<Text>| {
+                if !response.status().is_success() {
+                    return Message::ZoneLoadError(anyhow!("Could not load {}: {}", url, response.status()));
+                }
+                match response.into_body() {
+                    Ok(msg) => Message::ZoneLoad(url.clone(), msg),
+                    Err(err) => Message::ZoneLoadError(err),
+                }
+            }
+        });
+
+        let task = FetchService::fetch(request, callback)?;
+        self.zone_fetches.insert(url, task);
+        Ok(())
+    }
+
+    fn load_zone_file(&mut self, url: String, body: String) {
+        self.zone_fetches.remove(&url);
+        // Once one file has failed, the rest can't make a zone and would only
+        // hide the first error
+        if self.zone_error.is_some() || self.load_error.is_some() {
+            return
+        }
+        ConsoleService::info(&format!("Parsing Zone: {}", url));
+
+        let file = match ZoneFile::parse(url.clone(), body) {
+            Ok(file) => file,
+            Err(err) => {
+                ConsoleService::error(&err.to_string());
+                self.zone_error = Some(err);
+                return
+            },
+        };
+
+        let includes: Vec<String> = file.includes().into_iter()
+            .map(|path| resolve_include(&url, path))
+            .collect();
+        self.files.insert(url, file);
+
+        for include in includes {
+            if !self.files.contains_key(&include) && !self.zone_fetches.contains_key(&include) {
+                if let Err(err) = self.fetch_zone_file(include) {
+                    self.load_error = Some(err);
+                    return
+                }
+            }
+        }
+
+        if self.zone_fetches.is_empty() {
+            match assemble(&self.zone_url, &self.files, resolve_include) {
+                Ok(zone) => {
+                    for diagnostic in lint(&zone) {
+                        match diagnostic.severity {
+                            Severity::Error => ConsoleService::error(&diagnostic.to_string()),
+                            Severity::Warning => ConsoleService::warn(&diagnostic.to_string()),
+                        }
+                    }
+                    if let Some(title) = &zone.meta.title {
+                        yew::utils::document().set_title(title);
+                    }
+                    self.zone = Some(zone)
+                },
+                Err(err) => {
+                    ConsoleService::error(&err.to_string());
+                    self.zone_error = Some(err);
+                },
+            }
+        }
+    }
 }
 
 impl Component for Root {
@@ -63,47 +153,47 @@ impl Component for Root {
             Ok(url) => url,
             Err(err) => {
                 return Self {
-                    _link: link,
-                    _zone_fetch: None,
+                    link,
+                    zone_fetches: Default::default(),
+                    zone_url: String::from(""),
+                    files: Default::default(),
                     zone: None,
                     session_key: String::from(""),
                     load_error: Some(err),
+                    zone_error: None,
                 }
             },
         };
 
-        ConsoleService::info(&format!("Loading zone at: {}", url.to_string()));        
-        ConsoleService::info(&format!("Using session: {}", session));        
-
-        let request = Request::get(url.to_string())
-            .body(Nothing)
-            .expect("Could not build that request.");
+        ConsoleService::info(&format!("Using session: {}", session));
 
-        let callback = link.callback(|response: Response<Text>| {
-            match response.into_body() {
-                Ok(msg) => Message::ZoneLoad(msg),
-                Err(err) => Message::ZoneLoadError(err),
-            }
-        });          
- 
-        let task = FetchService::fetch(request, callback).expect("failed to start request");                // 4. store the task so it isn't canceled immediately                self.fetch_task = Some(task);
-
-        Self {
-            _link: link,
-            _zone_fetch: Some(task),
+        let mut root = Self {
+            link,
+            zone_fetches: Default::default(),
+            zone_url: url.to_string(),
+            files: Default::default(),
             zone: None,
             session_key: session,
             load_error: None,
+            zone_error: None,
+        };
+        if let Err(err) = root.fetch_zone_file(url.to_string()) {
+            root.load_error = Some(err);
         }
+        root
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
-            Message::ZoneLoad(load) => {
-                self.zone = build_world(load);
-                true    
+            Message::ZoneLoad(url, body) => {
+                self.load_zone_file(url, body);
+                true
             },
             Message::ZoneLoadError(err) => {
+                // Keep whichever went wrong first
+                if self.zone_error.is_some() || self.load_error.is_some() {
+                    return false
+                }
                 self.load_error = Some(err);
                 true
             },
@@ -121,6 +211,16 @@ impl Component for Root {
             }
         }
 
+        if let Some(error) = &self.zone_error {
+            // The same message the command line tools print, excerpt and all
+            return html! {
+                <div class="content zone-error">
+                    <h3>{"This story could not be loaded"}</h3>
+                    <pre>{error.to_string()}</pre>
+                </div>
+            }
+        }
+
         match &self.zone {
             Some(zone) => {
                 html! {
@@ -0,0 +1,331 @@
+use std::rc::Rc;
+
+use nom::branch::alt;
+use nom::bytes::complete::tag;
+use nom::character::complete::{char, digit1};
+use nom::combinator::{cut, eof, map, map_res, opt, recognize};
+use nom::error::context;
+use nom::multi::{many0, many_till, separated_list1};
+use nom::sequence::{preceded, terminated, tuple};
+use nom::Err;
+
+use crate::engine::{Engine, Fragment, State, Status};
+use crate::zone::{entry_end, filter_expr, line_end, skip_ws, symbol, text_run, FilterOperation, LineMessage, LineNumbers, ParseResult, TextLink, Zone, ZoneError};
+
+enum Step {
+    Choose(String),
+    Continue,
+    ExpectScene(String),
+    ExpectStatus(Status),
+    ExpectText(String),
+    Expect(FilterOperation),
+}
+
+// Each step along with where it was written
+type Steps = Vec<(Step, usize)>;
+
+/// A playthrough of a zone written out ahead of time: the choices to make
+/// and what should be true along the way.
+pub struct Script {
+    name: String,
+    seed: u64,
+    steps: Steps,
+}
+
+impl Script {
+    pub fn parse(name: String, source: String) -> Result<Self, ZoneError> {
+        let (seed, steps) = match parse_script(&source) {
+            Ok((_, script)) => script,
+            Err(Err::Incomplete(_)) => return Err(ZoneError::at(&name, &source, "", String::from("unexpected end of file"))),
+            Err(Err::Error(err)) | Err(Err::Failure(err)) => return Err(ZoneError::from_parse(&name, &source, err)),
+        };
+
+        let lines = LineNumbers::new(&source);
+        let steps = steps.into_iter().map(|(step, position)| (step, lines.line(position))).collect();
+        Ok(Self { name, seed: seed.unwrap_or(0), steps })
+    }
+
+    /// Play the zone from the start, stopping at the first step that fails,
+    /// and give back the state play finished in.
+    ///
+    /// Between steps play carries on by itself until the story stops or
+    /// offers a choice, so steps only need to describe the decisions.
+    pub fn run(&self, zone: Rc<Zone>) -> Result<State, LineMessage> {
+        let state = State::with_seed(&zone, self.seed);
+        let mut engine = Engine::with_state(zone, state);
+        let mut since = 0;
+
+        for (step, line) in &self.steps {
+            let fail = |message: String| LineMessage { file: self.name.clone(), line: *line, message };
+            engine.settle().map_err(|err| fail(err.to_string()))?;
+
+            match step {
+                Step::Choose(choice) => {
+                    let view = engine.view().map_err(|err| fail(err.to_string()))?;
+                    let mut offered = Vec::new();
+                    let mut found = None;
+                    for fragment in view.lines.iter().flatten() {
+                        let (link, locked) = match fragment {
+                            Fragment::Link(link) => (link, None),
+                            Fragment::Locked(link, reason) => (link, Some(reason)),
+                            Fragment::Text(_) => continue,
+                        };
+                        if found.is_none() && chosen_by(link, choice) {
+                            found = Some((link.clone(), locked));
+                        }
+                        offered.push(format!("'{}'", link.destination));
+                    }
+
+                    match found {
+                        Some((link, None)) => {
+                            engine.choose(&link).map_err(|err| fail(err.to_string()))?;
+                            since = engine.state().log.len();
+                        },
+                        Some((_, Some(reason))) if reason.is_empty() => {
+                            return Err(fail(format!("choice '{}' is locked", choice)));
+                        },
+                        Some((_, Some(reason))) => {
+                            return Err(fail(format!("choice '{}' is locked: {}", choice, reason)));
+                        },
+                        None if offered.is_empty() => {
+                            return Err(fail(format!("expected to choose '{}' but no choices are offered", choice)));
+                        },
+                        None => {
+                            return Err(fail(format!("no choice '{}' is offered, only {}", choice, offered.join(", "))));
+                        },
+                    }
+                },
+                Step::Continue => {
+                    if !engine.step().map_err(|err| fail(err.to_string()))? {
+                        return Err(fail(String::from("there is no line to continue past")));
+                    }
+                },
+                Step::ExpectScene(label) => {
+                    let scene = &engine.state().scene;
+                    if scene != label {
+                        return Err(fail(format!("expected to be in scene '{}' but play is in '{}'", label, scene)));
+                    }
+                },
+                Step::ExpectStatus(status) => {
+                    let current = &engine.state().status;
+                    if current != status {
+                        return Err(fail(format!("expected the story to be {} but it is {}", status_name(status), status_name(current))));
+                    }
+                },
+                Step::ExpectText(text) => {
+                    let mut shown: Vec<String> = engine.state().log.iter().skip(since).map(|line| plain(line)).collect();
+                    let view = engine.view().map_err(|err| fail(err.to_string()))?;
+                    shown.extend(view.lines.iter().map(|line| line.iter().map(|fragment| match fragment {
+                        Fragment::Text(text) => text.as_str(),
+                        Fragment::Link(link) | Fragment::Locked(link, _) => link.text.as_str(),
+                    }).collect::<String>()));
+                    if !shown.iter().any(|line| line.contains(text.as_str())) {
+                        return Err(fail(format!("expected text containing '{}' since the last choice", text)));
+                    }
+                },
+                Step::Expect(op) => {
+                    let mut resolved = op.clone();
+                    resolve_visits(&mut resolved, engine.zone(), &engine.state().scene).map_err(fail)?;
+                    if engine.state().evaluate(&resolved).map_err(fail)? == 0 {
+                        return Err(fail(format!("expected '{}' to be true", op)));
+                    }
+                },
+            }
+        }
+
+        // Carry on after the last step too, so the state is where play stops
+        if let Some((_, line)) = self.steps.last() {
+            engine.settle().map_err(|err| LineMessage { file: self.name.clone(), line: *line, message: err.to_string() })?;
+        }
+        Ok(engine.state().clone())
+    }
+}
+
+// A link can be picked by where it leads, with or without the enclosing scene, or by its text
+fn chosen_by(link: &TextLink, choice: &str) -> bool {
+    link.destination == choice
+        || link.destination.rsplit('.').next() == Some(choice)
+        || link.text.trim() == choice
+}
+
+// Visit counts name scenes the way the zone would from where play is, or else
+// by the last part of their label the way choices do
+fn resolve_visits(op: &mut FilterOperation, zone: &Zone, scene: &str) -> Result<(), String> {
+    match op {
+        FilterOperation::OperatorCall(call) => {
+            resolve_visits(&mut call.left, zone, scene)?;
+            resolve_visits(&mut call.right, zone, scene)?;
+        },
+        FilterOperation::Random(call) => {
+            resolve_visits(&mut call.low, zone, scene)?;
+            resolve_visits(&mut call.high, zone, scene)?;
+        },
+        FilterOperation::CountVisits(label) => {
+            let found = zone.resolve(scene, label).or_else(|| {
+                zone.scenes().iter().find(|known| known.label.rsplit('.').next() == Some(label.as_str())).map(|known| known.label.clone())
+            });
+            match found {
+                Some(found) => *label = found,
+                None => return Err(format!("there is no scene '{}' to count visits to", label)),
+            }
+        },
+        FilterOperation::IntLiteral(_) | FilterOperation::CountItems(_) | FilterOperation::ReadVariable(_) => {},
+    }
+    Ok(())
+}
+
+fn status_name(status: &Status) -> &'static str {
+    match status {
+        Status::Running => "running",
+        Status::Finished => "finished",
+        Status::Reset => "reset",
+    }
+}
+
+// Lines in the log carry markup for disabled links, only the words matter here
+fn plain(line: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {},
+        }
+    }
+    out
+}
+
+// script = ${ SOI ~ empty_line* ~ seed? ~ step* ~ EOI }
+fn parse_script(input: &str) -> ParseResult<'_, (Option<u64>, Steps)> {
+    let (input, _) = many0(line_end)(input)?;
+    let (input, seed) = opt(seed)(input)?;
+    let (input, (steps, _)) = many_till(
+        terminated(preceded(skip_ws, cut(context("'choose', 'continue' or 'expect'", step))), cut(entry_end)),
+        eof,
+    )(input)?;
+    Ok((input, (seed, steps)))
+}
+
+// seed = ${ "seed" ~ whitespace+ ~ ASCII_DIGIT+ ~ line_end+ }
+fn seed(input: &str) -> ParseResult<'_, u64> {
+    preceded(
+        tuple((skip_ws, tag("seed "), skip_ws)),
+        cut(context("seed", terminated(map_res(digit1, str::parse), entry_end))),
+    )(input)
+}
+
+// step = ${ choose | continue | expect }
+fn step(input: &str) -> ParseResult<'_, (Step, usize)> {
+    let position = input.len();
+    let (input, step) = alt((choose_step, continue_step, expect_step))(input)?;
+    Ok((input, (step, position)))
+}
+
+// choose = ${ "choose" ~ whitespace+ ~ text }
+fn choose_step(input: &str) -> ParseResult<'_, Step> {
+    let (input, choice) = preceded(tag("choose "), cut(context("choice", preceded(skip_ws, text_run("/\\`\r\n")))))(input)?;
+    Ok((input, Step::Choose(choice.trim_end().to_string())))
+}
+
+// continue = ${ "continue" }
+fn continue_step(input: &str) -> ParseResult<'_, Step> {
+    map(tag("continue"), |_| Step::Continue)(input)
+}
+
+// expect = ${ "expect" ~ whitespace+ ~ (expect_scene | expect_status | expect_text | filter_expr) }
+fn expect_step(input: &str) -> ParseResult<'_, Step> {
+    preceded(tag("expect "), cut(context("expectation", preceded(skip_ws, alt((
+        expect_scene,
+        expect_status,
+        expect_text,
+        map(filter_expr, Step::Expect),
+    ))))))(input)
+}
+
+// expect_scene = ${ "scene" ~ whitespace+ ~ symbol ~ ("." ~ symbol)* }
+fn expect_scene(input: &str) -> ParseResult<'_, Step> {
+    let (input, label) = preceded(tuple((tag("scene "), skip_ws)), recognize(separated_list1(char('.'), symbol)))(input)?;
+    Ok((input, Step::ExpectScene(label.to_string())))
+}
+
+// expect_status = ${ "status" ~ whitespace+ ~ ("running" | "finished" | "reset") }
+fn expect_status(input: &str) -> ParseResult<'_, Step> {
+    preceded(tuple((tag("status "), skip_ws)), alt((
+        map(tag("running"), |_| Step::ExpectStatus(Status::Running)),
+        map(tag("finished"), |_| Step::ExpectStatus(Status::Finished)),
+        map(tag("reset"), |_| Step::ExpectStatus(Status::Reset)),
+    )))(input)
+}
+
+// expect_text = ${ "text" ~ whitespace+ ~ text }
+fn expect_text(input: &str) -> ParseResult<'_, Step> {
+    let (input, text) = preceded(tuple((tag("text "), skip_ws)), text_run("/\\`\r\n"))(input)?;
+    Ok((input, Step::ExpectText(text.trim_end().to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::build_world;
+
+    const ZONE: &str = "default: ??\n    ? Which way?\n    [left | Go left.]\n    [right | Go right.]\n\n    left:\n        The left path.\n        *set gold = 3\n        *end\n\n    right:\n        The right path.\n        *reset\n";
+
+    fn run(script: &str) -> Result<State, LineMessage> {
+        Script::parse(String::from("test"), script.to_string()).unwrap().run(build_world(ZONE.to_string()).unwrap())
+    }
+
+    #[test]
+    fn steps_are_parsed_with_their_lines() {
+        let script = Script::parse(String::new(), String::from("seed 7\n\nchoose left\nchoose Go right.  // by text\ncontinue\nexpect scene default.left\nexpect status finished\nexpect text The left path\nexpect gold == 3\n")).unwrap();
+        assert_eq!(script.seed, 7);
+        let lines: Vec<usize> = script.steps.iter().map(|(_, line)| *line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7, 8, 9]);
+        let steps: Vec<&Step> = script.steps.iter().map(|(step, _)| step).collect();
+        assert!(matches!(steps[0], Step::Choose(choice) if choice == "left"));
+        assert!(matches!(steps[1], Step::Choose(choice) if choice == "Go right."));
+        assert!(matches!(steps[2], Step::Continue));
+        assert!(matches!(steps[3], Step::ExpectScene(label) if label == "default.left"));
+        assert!(matches!(steps[4], Step::ExpectStatus(Status::Finished)));
+        assert!(matches!(steps[5], Step::ExpectText(text) if text == "The left path"));
+        assert!(matches!(steps[6], Step::Expect(op) if op.to_string() == "gold == 3"));
+    }
+
+    #[test]
+    fn parse_errors_are_located() {
+        let error = |script: &str| Script::parse(String::from("bad.test"), script.to_string()).err().unwrap();
+        let err = error("choose left\n\nexpect colour blue\n");
+        assert_eq!((err.file.as_str(), err.line), ("bad.test", 3));
+        assert_eq!(error("choose left\njump\n").line, 2);
+    }
+
+    #[test]
+    fn passing_scripts_give_back_the_final_state() {
+        let state = run("choose left\nexpect text The left path\nexpect gold == 3\nexpect #left == 1\nexpect #default.right == 0\nexpect scene default.left\nexpect status finished\n").unwrap();
+        assert_eq!(state.status, Status::Finished);
+
+        let state = run("choose Go right.\nexpect status reset\n").unwrap();
+        assert_eq!(state.status, Status::Reset);
+    }
+
+    #[test]
+    fn failing_steps_are_reported_where_written() {
+        let fail = |script: &str| run(script).unwrap_err().to_string();
+        assert_eq!(fail("choose left\nexpect gold == 4\n"), "test, line 2: expected 'gold == 4' to be true");
+        assert_eq!(fail("choose up\n"), "test, line 1: no choice 'up' is offered, only 'default.left', 'default.right'");
+        assert_eq!(fail("\nchoose left\nexpect scene default.right\n"), "test, line 3: expected to be in scene 'default.right' but play is in 'default.left'");
+        assert_eq!(fail("choose right\nexpect status finished\n"), "test, line 2: expected the story to be finished but it is reset");
+        assert_eq!(fail("choose left\nexpect text The right path\n"), "test, line 2: expected text containing 'The right path' since the last choice");
+        assert_eq!(fail("choose left\nexpect #middle == 0\n"), "test, line 2: there is no scene 'middle' to count visits to");
+    }
+
+    #[test]
+    fn example_script_passes() {
+        let zone = build_world(include_str!("../static/items.zone").to_string()).unwrap();
+        let script = Script::parse(String::from("static/items.test"), include_str!("../static/items.test").to_string()).unwrap();
+        if let Err(failure) = script.run(zone) {
+            panic!("{}", failure);
+        }
+    }
+}
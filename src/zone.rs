@@ -1,19 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::rc::Rc;
 
 use nom::branch::alt;
-use nom::character::complete::{alphanumeric1, digit1, line_ending, multispace0};
-use nom::error::{ParseError, VerboseError, convert_error};
-use nom::multi::{many0, many1, many_till};
+use nom::character::complete::{alphanumeric1, char, digit1, line_ending, multispace0, none_of, not_line_ending};
+use nom::error::{ErrorKind, ParseError, VerboseError, VerboseErrorKind, context};
+use nom::multi::{many0, many1, many_till, separated_list1};
 use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
-use nom::bytes::complete::{is_a, tag};
+use nom::bytes::complete::{is_a, is_not, tag};
 use nom::{IResult, Err};
-use nom::combinator::{eof, opt};
+use nom::combinator::{cut, eof, map, not, opt, peek, recognize};
 
 use serde::{Deserialize, Serialize};
-use yew::services::ConsoleService;
 
-fn parent(val: &String) -> String {
+pub mod graph;
+pub mod lint;
+
+fn parent(val: &str) -> String {
     match val.rfind('.') {
         Some(point) => {
             val[0..point].to_string()
@@ -22,16 +26,48 @@ fn parent(val: &String) -> String {
     }
 }
 
+// The scene `label` names when written inside scene `from`: a sub scene of
+// `from` or of a scene enclosing it, or else a top level scene
+fn resolve_label(from: &str, label: &str, exists: impl Fn(&str) -> bool) -> Option<String> {
+    let mut prefix = from.to_string() + ".1";
+    while !prefix.is_empty() {
+        prefix = parent(&prefix);
+        let mut alt = prefix.clone() + "." + label;
+        alt = alt.strip_prefix(".").unwrap_or(&alt).to_string();
+        if exists(&alt) {
+            return Some(alt);
+        }
+    }
+    None
+}
+
 
 #[derive(Debug, Clone)]
 pub struct ItemCommand {
     pub change: HashMap<String, i32>
 }
 
+#[derive(Debug, Clone)]
+pub enum SetOperator {
+    Assign,
+    Add,
+    Sub,
+    Mul,
+}
+
 #[derive(Debug, Clone)]
 pub struct SetCommand {
     pub name: String,
-    pub value: i32
+    pub operator: SetOperator,
+    pub value: FilterOperation,
+}
+
+#[derive(Debug, Clone)]
+pub struct RollCommand {
+    pub name: String,
+    pub count: u32,
+    pub sides: u32,
+    pub modifier: i32,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -60,6 +96,9 @@ pub enum Command {
     End,
     Reset,
     Set(SetCommand),
+    Roll(RollCommand),
+    Call(String),
+    Return,
 }
 
 #[derive(Debug, Clone)]
@@ -85,30 +124,104 @@ pub struct OperatorCall {
     pub right: FilterOperation,
 }
 
+#[derive(Debug, Clone)]
+pub struct RandomCall {
+    pub low: FilterOperation,
+    pub high: FilterOperation,
+}
+
 #[derive(Debug, Clone)]
 pub enum FilterOperation {
     OperatorCall(Box<OperatorCall>),
+    Random(Box<RandomCall>),
     IntLiteral(i32),
     CountVisits(String),
     CountItems(String),
     ReadVariable(String),
 }
 
+impl fmt::Display for Ops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Ops::Add => "+",
+            Ops::Sub => "-",
+            Ops::Mul => "*",
+            Ops::Div => "/",
+            Ops::Gt => ">",
+            Ops::Gte => ">=",
+            Ops::Lt => "<",
+            Ops::Lte => "<=",
+            Ops::Eq => "==",
+            Ops::Ne => "!=",
+            Ops::And => "and",
+            Ops::Or => "or",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+// Written back out in zone syntax, with nested operations in brackets
+impl fmt::Display for FilterOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nested = |op: &FilterOperation| match op {
+            FilterOperation::OperatorCall(_) => format!("({})", op),
+            _ => op.to_string(),
+        };
+        match self {
+            FilterOperation::OperatorCall(call) => write!(f, "{} {} {}", nested(&call.left), call.operator, nested(&call.right)),
+            FilterOperation::Random(call) => write!(f, "rand({}, {})", call.low, call.high),
+            FilterOperation::IntLiteral(value) => write!(f, "{}", value),
+            FilterOperation::CountVisits(label) => write!(f, "#{}", label),
+            FilterOperation::CountItems(item) => write!(f, "${}", item),
+            FilterOperation::ReadVariable(name) => write!(f, "{}", name),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LineFilter {
-    pub operation: FilterOperation
+    pub operation: FilterOperation,
+    /// Show a text line with its links disabled rather than hiding it when
+    /// the filter fails, giving this reason (which may be empty).
+    pub locked: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkKind {
+    /// Offered every time the line is shown
+    Sticky,
+    /// Taken at most once per scene
+    Once,
+    /// Only offered once every once-only link in the scene has been taken
+    Fallback,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TextLink {
     pub destination: String,
-    pub text: String
+    pub text: String,
+    pub kind: LinkKind,
+    /// Index of the line offering the link within its scene.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum ItemField {
+    Name,
+    Details,
+}
+
+#[derive(Debug, Clone)]
+pub enum Interpolation {
+    Value(FilterOperation),
+    Item(String, ItemField),
 }
 
 #[derive(Debug, Clone)]
 pub enum TextPart {
     Link(TextLink),
-    Text(String)
+    Text(String),
+    Interpolation(Interpolation),
 }
 
 #[derive(Debug, Clone)]
@@ -118,10 +231,25 @@ pub struct TextLine {
     pub parts: Vec<TextPart>
 }
 
+#[derive(Debug, Clone)]
+pub struct CommandLine {
+    pub filter: Option<LineFilter>,
+    pub command: Command,
+}
+
+/// Continue from another line of the scene unless the filter passes.
+/// `*if` blocks are laid out as a series of these.
+#[derive(Debug, Clone)]
+pub struct Jump {
+    pub unless: Option<LineFilter>,
+    pub target: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum Line {
     TextLine(TextLine),
-    CommandLine(Command)
+    CommandLine(CommandLine),
+    Jump(Jump),
 }
 
 #[derive(Debug, Clone)]
@@ -129,35 +257,55 @@ pub struct Scene {
     pub label: String,
     pub branch: bool,
     pub lines: Vec<Line>,
+    /// Where the scene was written, and the source line of each of its lines
+    pub file: String,
+    pub source_line: usize,
+    pub line_sources: Vec<usize>,
 }
 
 impl Scene {
-    fn _update_labels(&mut self, names: &Vec<String>){
+    fn _update_labels(&mut self, names: &[String]){
         self.lines = self.lines.clone().into_iter().map(|mut line|{
             match &mut line {
                 Line::TextLine(text) => {
                     if let Some(filter) = &mut text.filter {
-                        self._update_filter_operation_labels(&names, &mut filter.operation);
+                        self._update_filter_operation_labels(names, &mut filter.operation);
                     }
                     for part in &mut text.parts {
                         match part {
                             TextPart::Link(link) => {
-                                link.destination = self._fix_label(&names, &link.destination)
+                                link.destination = self._fix_label(names, &link.destination)
                             },
                             TextPart::Text(_) => {},
+                            TextPart::Interpolation(Interpolation::Value(op)) => {
+                                self._update_filter_operation_labels(names, op);
+                            },
+                            TextPart::Interpolation(Interpolation::Item(..)) => {},
                         }
                     }
                 },
-                Line::CommandLine(command) => {
-                    match command {
+                Line::CommandLine(line) => {
+                    if let Some(filter) = &mut line.filter {
+                        self._update_filter_operation_labels(names, &mut filter.operation);
+                    }
+                    match &mut line.command {
                         Command::Item(_) => {},
                         Command::Reset => {},
                         Command::End => {},
-                        Command::Next(value) => {
-                            *value = self._fix_label(&names, value);
+                        Command::Next(value) | Command::Call(value) => {
+                            *value = self._fix_label(names, value);
                         }
-                        Command::Set(_) => {},
+                        Command::Return => {},
+                        Command::Set(cmd) => {
+                            self._update_filter_operation_labels(names, &mut cmd.value);
+                        },
                         Command::SetItem(_) => {},
+                        Command::Roll(_) => {},
+                    }
+                },
+                Line::Jump(jump) => {
+                    if let Some(filter) = &mut jump.unless {
+                        self._update_filter_operation_labels(names, &mut filter.operation);
                     }
                 },
             }
@@ -188,34 +336,59 @@ impl Scene {
         // }
     }
 
-    fn _update_filter_operation_labels(&mut self, names: &Vec<String>, op: &mut FilterOperation) {
+    fn _update_filter_operation_labels(&mut self, names: &[String], op: &mut FilterOperation) {
         match op {
             FilterOperation::OperatorCall(call) => {
-                self._update_filter_operation_labels(&names, &mut call.left);
-                self._update_filter_operation_labels(&names, &mut call.right);
+                self._update_filter_operation_labels(names, &mut call.left);
+                self._update_filter_operation_labels(names, &mut call.right);
+            },
+            FilterOperation::Random(call) => {
+                self._update_filter_operation_labels(names, &mut call.low);
+                self._update_filter_operation_labels(names, &mut call.high);
             },
             FilterOperation::IntLiteral(_) => {},
             FilterOperation::CountVisits(count) => {
-                *count = self._fix_label(&names, &count);
+                *count = self._fix_label(names, count);
             },
             FilterOperation::CountItems(_) => {},
             FilterOperation::ReadVariable(_) => {},
         }
     }
 
-    fn _fix_label(&self, names: &Vec<String>, old: &String) -> String {
-        let mut prefix = self.label.clone() + ".1";
-        while prefix.len() > 0 {            
-            prefix = parent(&prefix);
-            let mut alt = prefix.clone() + "." + old;
-            alt = alt.strip_prefix(".").unwrap_or(&alt).to_string();
-            if names.contains(&alt){
-                return alt;
-            }    
-        }
+    fn _fix_label(&self, names: &[String], old: &str) -> String {
+        // Left as written when nothing matches, lint reports it
+        resolve_label(&self.label, old, |name| names.iter().any(|known| known == name)).unwrap_or_else(|| old.to_string())
+    }
+}
 
-        ConsoleService::error(&format!("Bad link '{}' in '{}'", old, self.label));
-        return old.clone();
+/// Descriptive information set in the header of the root zone file.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneMeta {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub start: Option<String>,
+    pub description: Option<String>,
+}
+
+enum MetaField {
+    Title,
+    Author,
+    Version,
+    Start,
+    Description,
+}
+
+impl ZoneMeta {
+    fn set(&mut self, field: &MetaField, value: String) {
+        let slot = match field {
+            MetaField::Title => &mut self.title,
+            MetaField::Author => &mut self.author,
+            MetaField::Version => &mut self.version,
+            MetaField::Start => &mut self.start,
+            MetaField::Description => &mut self.description,
+        };
+        *slot = Some(value);
     }
 }
 
@@ -223,19 +396,28 @@ impl Scene {
 pub struct Zone {
     scenes: Vec<Scene>,
     lookup: HashMap<String, usize>,
+    counted: BTreeSet<String>,
     pub initialize: Vec<Command>,
+    pub meta: ZoneMeta,
 }
 
 impl Zone {
-    fn new(scenes: Vec<Scene>, initialize: Vec<Command>) -> Self {
+    fn new(scenes: Vec<Scene>, initialize: Vec<Command>, meta: ZoneMeta) -> Self {
         let lookup = scenes.iter().enumerate().map(|(i, s)| (s.label.clone(), i)).collect();
         Self {
             scenes,
             lookup,
+            counted: BTreeSet::new(),
             initialize,
+            meta,
         }
     }
 
+    /// The scene a new playthrough begins in.
+    pub fn start(&self) -> String {
+        self.meta.start.clone().unwrap_or_else(|| String::from("default"))
+    }
+
     fn scene_names(&self) -> Vec<String> {
         self.scenes.iter()
             .map(|s| s.label.clone())
@@ -247,34 +429,67 @@ impl Zone {
         for sec in &mut self.scenes {
             sec._update_labels(&names);
         }
-    }
 
-    pub fn find_scene(&self, name: &String) -> &Scene {
-        if let Some(&index) = self.lookup.get(name) {
-            return &self.scenes[index];
+        let mut counted = BTreeSet::new();
+        for scene in &self.scenes {
+            for line in &scene.lines {
+                for op in lint::expressions(line) {
+                    lint::walk(op, &mut |op| {
+                        if let FilterOperation::CountVisits(target) = op {
+                            counted.insert(target.clone());
+                        }
+                    });
+                }
+            }
         }
-        ConsoleService::error(&format!("Bad scene name: {}", name));
-        panic!{}
+        self.counted = counted;
+    }
+
+    /// Every scene in the order they were written, sub-scenes after their parent.
+    pub fn scenes(&self) -> &[Scene] {
+        &self.scenes
+    }
+
+    /// Scenes whose visit count something in the zone reads. Visits to any
+    /// other scene make no difference to how the story plays.
+    pub fn counted(&self) -> &BTreeSet<String> {
+        &self.counted
+    }
+
+    /// The scene with this label, if there is one.
+    pub fn scene(&self, label: &str) -> Option<&Scene> {
+        self.lookup.get(label).map(|&index| &self.scenes[index])
+    }
+
+    /// The scene a label written inside scene `from` refers to, the same way
+    /// links and visit counts in the zone are looked up.
+    pub fn resolve(&self, from: &str, label: &str) -> Option<String> {
+        resolve_label(from, label, |name| self.lookup.contains_key(name))
+    }
+
+    /// The file and source line a line of a scene came from. Positions past
+    /// the end of the scene are put at the scene's label.
+    pub fn locate(&self, label: &str, line: usize) -> Option<(String, usize)> {
+        let scene = self.scene(label)?;
+        Some((scene.file.clone(), scene.line_sources.get(line).copied().unwrap_or(scene.source_line)))
     }
 
     // pub fn link_to(&self, at: &Vec<String>, link: String) -> Vec<String> {
     //     todo!{}
     // }
 
-    pub fn next(&self, last: &String) -> String {
-        let here = self.find_scene(last);
-        let up = parent(&here.label);
-        if up != "" && self.find_scene(&up).branch {
-            return up;
-        }
-        let start = self.lookup.get(last).unwrap_or(&0) + 1;
-        for scene in self.scenes[start..].iter() {
-            if parent(&scene.label) == up {
-                return scene.label.clone();
+    /// The scene play moves on to when `last` runs out of lines, if any.
+    pub fn following(&self, last: &str) -> Option<String> {
+        let index = *self.lookup.get(last)?;
+        let up = parent(&self.scenes[index].label);
+        if let Some(&parent_index) = self.lookup.get(&up) {
+            if self.scenes[parent_index].branch {
+                return Some(up);
             }
         }
-        ConsoleService::error(&format!("Bad scene name: {}", last));
-        panic!()
+        self.scenes[index + 1..].iter()
+            .find(|scene| parent(&scene.label) == up)
+            .map(|scene| scene.label.clone())
     }
 
     // pub fn next_in(&self, path: &Vec<String>, last: &String) -> String {
@@ -283,41 +498,282 @@ impl Zone {
 }
 
 
-pub fn build_world(data: String) -> Option<Rc<Zone>> {
-    ConsoleService::info("Parsing Zone");
-    // ConsoleService::info(&data);
-    
-    match parse_zone(&data) {
-        Ok((extra, mut zone)) => {
-            if extra.len() > 0 {
-                ConsoleService::error(&format!("Remaining {}", extra));
+/// A problem found while reading a zone file, located in the source text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub excerpt: String,
+    pub message: String,
+}
+
+impl ZoneError {
+    pub(crate) fn at(file: &str, data: &str, remaining: &str, message: String) -> Self {
+        let offset = data.len() - remaining.len();
+        let line_start = data[..offset].rfind('\n').map(|index| index + 1).unwrap_or(0);
+        let line_end = data[offset..].find('\n').map(|index| offset + index).unwrap_or(data.len());
+        Self {
+            file: file.to_string(),
+            line: data[..offset].matches('\n').count() + 1,
+            column: data[line_start..offset].chars().count() + 1,
+            excerpt: data[line_start..line_end].trim_end_matches('\r').to_string(),
+            message,
+        }
+    }
+
+    pub(crate) fn from_parse(file: &str, data: &str, err: VerboseError<&str>) -> Self {
+        let (remaining, kind) = match err.errors.first() {
+            Some(first) => first,
+            None => return Self::at(file, data, data, String::from("could not parse zone")),
+        };
+
+        // A context wrapped directly around the failing parser names what was expected
+        let mut rest = err.errors.iter().skip(1).peekable();
+        let mut message = match kind {
+            VerboseErrorKind::Char(c) => format!("expected '{}'", c),
+            VerboseErrorKind::Context(context) => context.to_string(),
+            VerboseErrorKind::Nom(ErrorKind::Digit) => String::from("expected a number"),
+            VerboseErrorKind::Nom(ErrorKind::AlphaNumeric) => String::from("expected a name"),
+            VerboseErrorKind::Nom(_) => {
+                // Skip past combinators such as alt that failed at the same place
+                while matches!(rest.peek(), Some((at, VerboseErrorKind::Nom(_))) if at == remaining) {
+                    rest.next();
+                }
+                match rest.peek() {
+                    Some((at, VerboseErrorKind::Context(context))) if at == remaining => {
+                        rest.next();
+                        format!("expected {}", context)
+                    },
+                    _ => String::from("unexpected text"),
+                }
+            },
+        };
+
+        let context = rest.find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(context) => Some(context),
+            _ => None,
+        });
+        if let Some(context) = context {
+            message = format!("{} in {}", message, context);
+        }
+
+        Self::at(file, data, remaining, message)
+    }
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, &self.file, self.line)?;
+        writeln!(f, ", column {}: {}", self.column, self.message)?;
+        writeln!(f, "{}", self.excerpt)?;
+        write!(f, "{}^", " ".repeat(self.column - 1))
+    }
+}
+
+impl std::error::Error for ZoneError {}
+
+/// Turns positions found while parsing, kept as the length of the input still
+/// remaining, into line numbers. Where each line starts is worked out once so
+/// a long file isn't read again for every position.
+pub(crate) struct LineNumbers {
+    length: usize,
+    breaks: Vec<usize>,
+}
+
+impl LineNumbers {
+    pub(crate) fn new(source: &str) -> Self {
+        let breaks = source.match_indices('\n').map(|(index, _)| index).collect();
+        Self { length: source.len(), breaks }
+    }
+
+    /// The line, counting from 1, of the position `remaining` bytes before the end.
+    pub(crate) fn line(&self, remaining: usize) -> usize {
+        let offset = self.length - remaining;
+        self.breaks.partition_point(|&index| index < offset) + 1
+    }
+}
+
+/// Write where a source line is, leaving out the file when it isn't known.
+pub(crate) fn write_location(f: &mut fmt::Formatter<'_>, file: &str, line: usize) -> fmt::Result {
+    if !file.is_empty() {
+        write!(f, "{}, ", file)?;
+    }
+    write!(f, "line {}", line)
+}
+
+/// A message about one line of a source file, such as a script step that
+/// didn't go as expected or a line no playthrough reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineMessage {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, &self.file, self.line)?;
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for LineMessage {}
+
+enum Part {
+    Scenes(Vec<Scene>, usize),
+    Include(String, usize),
+}
+
+/// A single parsed zone file, before any files it includes are merged in.
+pub struct ZoneFile {
+    name: String,
+    source: String,
+    parts: Vec<Part>,
+    initialize: Vec<Command>,
+    meta: ZoneMeta,
+    start_position: usize,
+}
+
+impl ZoneFile {
+    pub fn parse(name: String, source: String) -> Result<Self, ZoneError> {
+        let (mut parts, header) = match parse_zone(&source) {
+            Ok((extra, _)) if !extra.is_empty() => {
+                return Err(ZoneError::at(&name, &source, extra, String::from("unexpected text")));
+            },
+            Ok((_, zone)) => zone,
+            Err(Err::Incomplete(_)) => return Err(ZoneError::at(&name, &source, "", String::from("unexpected end of file"))),
+            Err(Err::Error(err)) | Err(Err::Failure(err)) => return Err(ZoneError::from_parse(&name, &source, err)),
+        };
+
+        let lines = LineNumbers::new(&source);
+        for part in &mut parts {
+            if let Part::Scenes(scenes, _) = part {
+                for scene in scenes {
+                    scene.file = name.clone();
+                    scene.source_line = lines.line(scene.source_line);
+                    for line in &mut scene.line_sources {
+                        *line = lines.line(*line);
+                    }
+                }
             }
+        }
 
-            for name in zone.scene_names(){
-                ConsoleService::info(&format!("{:}", name));                
+        let mut initialize = Vec::new();
+        let mut meta = ZoneMeta::default();
+        let mut start_position = source.len();
+        for entry in header {
+            match entry {
+                Header::Command(command) => initialize.push(command),
+                Header::Meta(field, value, position) => {
+                    if let MetaField::Start = field {
+                        start_position = position;
+                    }
+                    meta.set(&field, value);
+                },
             }
+        }
+        Ok(Self { name, source, parts, initialize, meta, start_position })
+    }
 
-            zone.correct();            
-            // ConsoleService::info(&format!("{:#?}", zone));
-            Some(Rc::new(zone))
-        },
-        Err(err) => {
-            let mess = match err {
-                Err::Incomplete(_) => "needed".to_string(),
-                Err::Error(err) => convert_error::<&str>(&data, err),
-                Err::Failure(err) => convert_error::<&str>(&data, err),
-            };
-            ConsoleService::error(&format!("{}", mess));
-            None
-        },
+    /// Paths named by `*include` directives, relative to this file.
+    pub fn includes(&self) -> Vec<&str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Include(path, _) => Some(path.as_str()),
+            Part::Scenes(..) => None,
+        }).collect()
+    }
+
+    fn error_at(&self, position: usize, message: String) -> ZoneError {
+        ZoneError::at(&self.name, &self.source, &self.source[self.source.len() - position..], message)
     }
 }
 
-type Result<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+/// Merge a zone file and everything it includes into one zone.
+///
+/// Files are looked up by the key `resolve` produces from the including
+/// file's key and the path written in the directive. A file included more
+/// than once is only merged the first time it is reached.
+pub fn assemble<F>(root: &str, files: &HashMap<String, ZoneFile>, resolve: F) -> Result<Rc<Zone>, ZoneError>
+    where F: Fn(&str, &str) -> String
+{
+    let mut merged = Merge {
+        files,
+        resolve: &resolve,
+        visited: Default::default(),
+        owners: Default::default(),
+        scenes: Default::default(),
+        initialize: Default::default(),
+    };
+    merged.add(root)?;
+
+    // Only the root file describes the story, included files just add scenes
+    let file = &files[root];
+    let mut zone = Zone::new(merged.scenes, merged.initialize, file.meta.clone());
+    if !zone.lookup.contains_key(&zone.start()) {
+        return Err(file.error_at(file.start_position, format!("start scene '{}' does not exist", zone.start())));
+    }
+    zone.correct();
+    Ok(Rc::new(zone))
+}
 
-fn ws<'a, F: 'a, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
+struct Merge<'a> {
+    files: &'a HashMap<String, ZoneFile>,
+    resolve: &'a dyn Fn(&str, &str) -> String,
+    visited: HashSet<String>,
+    owners: HashMap<String, String>,
+    scenes: Vec<Scene>,
+    initialize: Vec<Command>,
+}
+
+impl<'a> Merge<'a> {
+    fn add(&mut self, key: &str) -> Result<(), ZoneError> {
+        let file = &self.files[key];
+        self.visited.insert(key.to_string());
+        self.initialize.extend(file.initialize.iter().cloned());
+
+        for part in &file.parts {
+            match part {
+                Part::Scenes(scenes, position) => {
+                    let label = &scenes[0].label;
+                    match self.owners.get(label) {
+                        Some(owner) if owner != key => {
+                            return Err(file.error_at(*position, format!("scene '{}' is already defined in {}", label, owner)));
+                        },
+                        _ => {
+                            self.owners.insert(label.clone(), key.to_string());
+                        },
+                    }
+                    self.scenes.extend(scenes.iter().cloned());
+                },
+                Part::Include(path, position) => {
+                    let target = (self.resolve)(key, path);
+                    if self.visited.contains(&target) {
+                        continue;
+                    }
+                    if !self.files.contains_key(&target) {
+                        return Err(file.error_at(*position, format!("included file '{}' could not be loaded", path)));
+                    }
+                    self.add(&target)?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build a zone from a single file that doesn't include any others.
+pub fn build_world(data: String) -> Result<Rc<Zone>, ZoneError> {
+    let mut files = HashMap::new();
+    files.insert(String::new(), ZoneFile::parse(String::new(), data)?);
+    assemble("", &files, |_, path| path.to_string())
+}
+
+pub(crate) type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+
+fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
   where
-  F: Fn(&'a str) -> IResult<&'a str, O, E>,
+  F: 'a + Fn(&'a str) -> IResult<&'a str, O, E>,
 {
   delimited(
     multispace0,
@@ -326,41 +782,111 @@ fn ws<'a, F: 'a, O, E: ParseError<&'a str>>(inner: F) -> impl FnMut(&'a str) ->
   )
 }
 
+enum Header {
+    Command(Command),
+    Meta(MetaField, String, usize),
+}
+
 enum  Entry {
-    Line(Line),
-    Scene(Vec<Scene>)
+    Line(Line, usize),
+    Scene(Vec<Scene>),
+    Conditional(Vec<Branch>),
+    Alternative(Branch),
 }
 
-// zone = ${ SOI ~ empty_line* ~ scene* ~ whitespace? ~ EOI }
-fn parse_zone(input: &str) -> Result<Zone> {
-    let (input, _) = many0(line_end)(input)?;
+struct Branch {
+    position: usize,
+    filter: Option<LineFilter>,
+    indent: usize,
+    body: Vec<Entry>,
+}
+
+// Lay out entries as the lines of a single scene, turning conditional blocks
+// into jumps and collecting any sub scenes found along the way.
+fn flatten_entries(entries: Vec<Entry>, lines: &mut Vec<Line>, positions: &mut Vec<usize>, sections: &mut Vec<Scene>) {
+    for entry in entries {
+        match entry {
+            Entry::Line(mut line, position) => {
+                if let Line::TextLine(text) = &mut line {
+                    for part in &mut text.parts {
+                        if let TextPart::Link(link) = part {
+                            link.line = lines.len();
+                        }
+                    }
+                }
+                lines.push(line);
+                positions.push(position);
+            },
+            Entry::Scene(scenes) => sections.extend(scenes),
+            Entry::Conditional(branches) => {
+                let count = branches.len();
+                let mut exits = Vec::new();
+                for (index, branch) in branches.into_iter().enumerate() {
+                    let check = lines.len();
+                    let guarded = branch.filter.is_some();
+                    if guarded {
+                        lines.push(Line::Jump(Jump{unless: branch.filter, target: 0}));
+                        positions.push(branch.position);
+                    }
+                    flatten_entries(branch.body, lines, positions, sections);
+
+                    // The last branch can fall straight through
+                    if index + 1 < count {
+                        exits.push(lines.len());
+                        lines.push(Line::Jump(Jump{unless: None, target: 0}));
+                        positions.push(branch.position);
+                    }
+                    if guarded {
+                        let next = lines.len();
+                        if let Line::Jump(jump) = &mut lines[check] {
+                            jump.target = next;
+                        }
+                    }
+                }
 
-    let (input, init) = match terminated(
-        many0(terminated(header_command, many0(line_end))), 
-        pair(tag("---"), many1(line_end))
-    )(input) {
-        Result::Ok(val) => val,
-        Result::Err(_err) => {
-            // let mess = match err {
-            //     Err::Incomplete(_) => "needed".to_string(),
-            //     Err::Error(err) => convert_error::<&str>(&input, err),
-            //     Err::Failure(err) => convert_error::<&str>(&input, err),
-            // };
-            // ConsoleService::error(&format!("Header parsing:\n{}", mess));
-            (input, vec![])
+                let end = lines.len();
+                for exit in exits {
+                    if let Line::Jump(jump) = &mut lines[exit] {
+                        jump.target = end;
+                    }
+                }
+            },
+            // Merged into the preceding conditional while parsing
+            Entry::Alternative(_) => {},
         }
+    }
+}
+
+// zone = ${ SOI ~ empty_line* ~ header? ~ (include | scene)* ~ whitespace? ~ EOI }
+fn parse_zone(input: &str) -> ParseResult<'_, (Vec<Part>, Vec<Header>)> {
+    let (input, _) = many0(line_end)(input)?;
+
+    let (input, init) = many0(terminated(header_command, many0(line_end)))(input)?;
+    let (input, _) = if init.is_empty() {
+        opt(pair(tag("---"), many1(line_end)))(input)?
+    } else {
+        let (input, end) = cut(context("the end of the header '---'", pair(tag("---"), many1(line_end))))(input)?;
+        (input, Some(end))
     };
-    
-    // let (input, init) = opt(terminated(
-    //     many0(terminated(header_command, many1(line_end))), 
-    //     pair(tag("---"), many1(line_end))
-    // ))(input)?;
 
-    let (input, (values, _)) = many_till(parse_scene, eof)(input)?;
+    let (input, (parts, _)) = many_till(alt((include_directive, top_scene)), eof)(input)?;
 
-    // let init = init.unwrap_or(Default::default());
+    Ok((input, (parts, init)))
+}
 
-    Ok((input, Zone::new(values.concat(), init.into_iter().collect())))
+fn top_scene(input: &str) -> ParseResult<'_, Part> {
+    let (rest, scenes) = parse_scene(input)?;
+    Ok((rest, Part::Scenes(scenes, input.len())))
+}
+
+// include = ${ "*" ~ whitespace* ~ "include" ~ whitespace+ ~ path ~ line_end+ }
+fn include_directive(input: &str) -> ParseResult<'_, Part> {
+    let (rest, (_, path, _)) = tuple((
+        tuple((tag("*"), skip_ws, tag("include "))),
+        cut(context("include path", preceded(skip_ws, is_not(" \t\r\n")))),
+        cut(entry_end),
+    ))(input)?;
+    Ok((rest, Part::Include(path.to_string(), input.len())))
 }
 
 // scene = ${ dialog | branch }
@@ -368,29 +894,30 @@ fn parse_zone(input: &str) -> Result<Zone> {
 //     label ~ "??" ~ line_end+ 
 //     ~ (dialog_multiple_lines | dialog_single_line)
 // }
-fn parse_scene(input: &str) -> Result<Vec<Scene>> {
-    let (input, (label, _, query, _, entries)) = tuple((
-        label, skip_ws, opt(tag("??")), many1(line_end), dialog_multiple_lines
+fn parse_scene(input: &str) -> ParseResult<'_, Vec<Scene>> {
+    let position = input.len();
+    let (input, (label, _, query, _)) = tuple((
+        label, skip_ws, opt(tag("??")), many1(line_end)
     ))(input)?;
+    let (input, entries) = cut(context("scene body", dialog_multiple_lines))(input)?;
 
     let mut lines = Vec::new();
+    let mut positions = Vec::new();
     let mut sections = Vec::new();
-    for entry in entries {
-        match entry {
-            Entry::Line(line) => lines.push(line),
-            Entry::Scene(scenes) => {
-                for mut scene in scenes {
-                    scene.label = label.clone() + "." + &scene.label;
-                    sections.push(scene)
-                }
-            }
-        }
+    flatten_entries(entries, &mut lines, &mut positions, &mut sections);
+    for scene in &mut sections {
+        scene.label = label.clone() + "." + &scene.label;
     }
 
+    // Positions are kept as the length of the remaining input until the
+    // whole file has been read and they can be turned into line numbers
     let mut out = vec![Scene {
         label,
         branch: query.is_some(),
         lines,
+        file: String::new(),
+        source_line: position,
+        line_sources: positions,
     }];
     out.append(&mut sections);
 
@@ -398,77 +925,182 @@ fn parse_scene(input: &str) -> Result<Vec<Scene>> {
 }
 
 // label = ${ symbol ~ ":" }
-fn label(input: &str) -> Result<String> {
+fn label(input: &str) -> ParseResult<'_, String> {
     let (input, (text, _)) = pair(symbol, tag(":"))(input)?;
     Ok((input, text))
 }
 
-// line_end = _{ whitespace? ~ endl }
-// empty_line = _{ line_end | COMMENT }
-fn line_end(input: &str) -> Result<()> {   
-    let (input, _) = tuple((many0(tag(" ")), line_ending))(input)?;
+// line_end = _{ whitespace? ~ COMMENT? ~ endl }
+// empty_line = _{ line_end }
+pub(crate) fn line_end(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = tuple((many0(tag(" ")), opt(comment), line_ending))(input)?;
     Ok((input, ()))
 }
 
-// fn comment(input: &str) -> Result<()> {   
-// let (input, _) = tuple((tag("--"), is_not("\n\r")))(input)?;
-// Ok((input, ()))
-// }
+// COMMENT = _{ "//" ~ (!endl ~ ANY)* }
+fn comment(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = pair(tag("//"), not_line_ending)(input)?;
+    Ok((input, ()))
+}
 
 // dialog_multiple_lines = ${ 
 //     PUSH(whitespace) ~ line 
 //     ~ (PEEK ~ line)*
 //     ~ POP ~ line
 // }
-fn dialog_multiple_lines(input: &str) -> Result<Vec<Entry>> {
-    let (input, prefix) = is_a(" ")(input)?;
-    let (input, first) = parse_entry(input)?;
-    let (input, additional) = many0(pair(tag(prefix), parse_entry))(input)?;
-    let mut shifted = vec![first];
-    for (_, row) in additional {
-        shifted.push(row);
+fn dialog_multiple_lines(input: &str) -> ParseResult<'_, Vec<Entry>> {
+    let (start, prefix) = context("an indented line", is_a(" "))(input)?;
+    let (mut input, first) = parse_entry(start)?;
+    let mut shifted = Vec::new();
+    add_entry(&mut shifted, first, prefix, start)?;
+    loop {
+        match pair(tag(prefix), parse_entry)(input) {
+            Ok((rest, (_, row))) => {
+                add_entry(&mut shifted, row, prefix, &input[prefix.len()..])?;
+                input = rest;
+            },
+            Err(Err::Error(_)) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    // A line at this indentation that isn't a valid entry is a mistake in
+    // that line, not the end of the block.
+    if let Ok((rest, _)) = tag::<_, _, VerboseError<&str>>(prefix)(input) {
+        if let Err(Err::Error(err)) = parse_entry(rest) {
+            return Err(Err::Failure(err));
+        }
+    }
+
+    Ok((input, shifted))
+}
+
+// Attach `*elif` and `*else` to the `*if` they follow
+fn add_entry<'a>(entries: &mut Vec<Entry>, entry: Entry, prefix: &str, at: &'a str) -> Result<(), Err<VerboseError<&'a str>>> {
+    let fail = |message| Err(Err::Failure(VerboseError{errors: vec![(at, VerboseErrorKind::Context(message))]}));
+    let indent = match &entry {
+        Entry::Conditional(branches) => branches[0].indent,
+        Entry::Alternative(branch) => branch.indent,
+        _ => usize::MAX,
+    };
+    if indent <= prefix.len() {
+        return fail("the body of a conditional must be indented further than its '*if'");
+    }
+
+    match entry {
+        Entry::Alternative(branch) => match entries.last_mut() {
+            Some(Entry::Conditional(branches)) if branches.last().is_some_and(|last| last.filter.is_some()) => {
+                branches.push(branch);
+                Ok(())
+            },
+            _ => fail("'*elif' or '*else' without a matching '*if'"),
+        },
+        entry => {
+            entries.push(entry);
+            Ok(())
+        },
     }
-    return Ok((input, shifted));
 }
 
 // line = ${ (dialog | branch | command | text_line) ~ line_end+ }
-fn parse_entry(input: &str) -> Result<Entry> {
-    let (input, (entry, _)) = pair(alt((sub_block, command, text_line)), many0(line_end))(input)?;
-    Ok((input, entry))
+fn parse_entry(input: &str) -> ParseResult<'_, Entry> {
+    let (rest, entry) = alt((sub_block, conditional, command, text_line))(input)?;
+
+    // Blocks like sub scenes already consume their own line endings
+    let consumed = &input[..input.len() - rest.len()];
+    let (rest, _) = if consumed.ends_with('\n') {
+        many0(line_end)(rest)?
+    } else {
+        let (rest, _) = entry_end(rest)?;
+        (rest, vec![])
+    };
+    Ok((rest, entry))
 }
 
-fn sub_block(input: &str) -> Result<Entry> {
+pub(crate) fn entry_end(input: &str) -> ParseResult<'_, ()> {
+    let (input, _) = alt((many1(line_end), map(tuple((skip_ws, opt(comment), eof)), |_| vec![])))(input)?;
+    Ok((input, ()))
+}
+
+// conditional = ${ "*" ~ whitespace* ~ (("if" | "elif") ~ whitespace+ ~ filter_expr | "else") ~ ":" ~ line_end+ ~ dialog_multiple_lines }
+fn conditional(input: &str) -> ParseResult<'_, Entry> {
+    let position = input.len();
+    let (input, (_, _, keyword)) = tuple((tag("*"), skip_ws, alt((tag("if "), tag("elif "), tag("else")))))(input)?;
+    let (input, filter) = if keyword == "else" {
+        (input, None)
+    } else {
+        let (input, operation) = cut(context("condition", preceded(skip_ws, filter_expr)))(input)?;
+        (input, Some(LineFilter{operation, locked: None}))
+    };
+    let (input, (_, _, _, indent, body)) = cut(context("conditional block", tuple((
+        skip_ws, char(':'), many1(line_end), peek(is_a(" ")), dialog_multiple_lines
+    ))))(input)?;
+
+    let branch = Branch{position, filter, indent: indent.len(), body};
+    if keyword == "if " {
+        Ok((input, Entry::Conditional(vec![branch])))
+    } else {
+        Ok((input, Entry::Alternative(branch)))
+    }
+}
+
+fn sub_block(input: &str) -> ParseResult<'_, Entry> {
     let (input, scene) = parse_scene(input)?;
-    return Ok((input, Entry::Scene(scene)));
+    Ok((input, Entry::Scene(scene)))
 }
 
 // symbol = ${ ASCII_ALPHANUMERIC ~ (ASCII_ALPHANUMERIC | "-")* } 
-fn symbol(input: &str) -> Result<String> {
+pub(crate) fn symbol(input: &str) -> ParseResult<'_, String> {
     let (input, (first, parts)) = pair(alphanumeric1, many0(alt((alphanumeric1, is_a("-_")))))(input)?;
     Ok((input, first.to_string() + &parts.concat().to_string()))
 }
 
-fn var_symbol(input: &str) -> Result<String> {
+fn var_symbol(input: &str) -> ParseResult<'_, String> {
     let (input, (first, parts)) = pair(alphanumeric1, many0(alt((alphanumeric1, is_a("_")))))(input)?;
     Ok((input, first.to_string() + &parts.concat().to_string()))
 }
 
 
-// command = ${ "*" ~ whitespace* ~ (item_command) }
-fn command(input: &str) -> Result<Entry> {
-    let (input, (_, _, command)) = tuple((tag("*"), many0(tag(" ")), alt((set_item_command, item_command, next_command, end_command, reset_command, set_command))))(input)?;
-    let line = Line::CommandLine(command);
-    Ok((input, Entry::Line(line)))
+// command = ${ (line_filter ~ whitespace*)? ~ "*" ~ whitespace* ~ (item_command) }
+fn command(input: &str) -> ParseResult<'_, Entry> {
+    let position = input.len();
+    let (input, (filter, _, _, command)) = tuple((opt(terminated(line_filter, skip_ws)), tag("*"), many0(tag(" ")), cut(context("command", alt((set_item_command, item_command, next_command, end_command, reset_command, roll_command, call_command, return_command, set_command))))))(input)?;
+    let line = Line::CommandLine(CommandLine{filter, command});
+    Ok((input, Entry::Line(line, position)))
 }
 
-fn header_command(input: &str) -> Result<Command> {
-    preceded(tuple((tag("*"), many0(tag(" ")))), alt((set_item_command, set_command)))(input)
+fn header_command(input: &str) -> ParseResult<'_, Header> {
+    preceded(tuple((tag("*"), many0(tag(" ")), not(tag("include ")))), cut(context("header command", alt((
+        map(set_item_command, Header::Command),
+        meta_directive,
+        map(set_command, Header::Command),
+    )))))(input)
 }
 
-fn set_item_command(input: &str) -> Result<Command> {
-    let (input, key) = delimited(ws(tag("set_item")), symbol, line_end)(input)?;
-    let (input, prefix) = is_a(" ")(input)?;
-    let (input, first_line) = set_item_line(input)?;
+// meta = ${ ("title" | "author" | "version" | "description") ~ whitespace+ ~ text | "start" ~ whitespace+ ~ symbol }
+fn meta_directive(input: &str) -> ParseResult<'_, Header> {
+    let position = input.len();
+    let (input, field) = terminated(alt((
+        map(tag("title"), |_| MetaField::Title),
+        map(tag("author"), |_| MetaField::Author),
+        map(tag("version"), |_| MetaField::Version),
+        map(tag("start"), |_| MetaField::Start),
+        map(tag("description"), |_| MetaField::Description),
+    )), tag(" "))(input)?;
+    let (input, value) = if let MetaField::Start = field {
+        cut(context("scene name", preceded(skip_ws, symbol)))(input)?
+    } else {
+        cut(context("value", preceded(skip_ws, map(text_run("/\\`\r\n"), |text| text.trim_end().to_string()))))(input)?
+    };
+    let (input, _) = cut(entry_end)(input)?;
+    Ok((input, Header::Meta(field, value, position)))
+}
+
+fn set_item_command(input: &str) -> ParseResult<'_, Command> {
+    let (input, _) = pair(tag("set_item"), tag(" "))(input)?;
+    let (input, (key, prefix, first_line)) = cut(context("item definition", tuple((
+        delimited(skip_ws, symbol, many1(line_end)), is_a(" "), set_item_line
+    ))))(input)?;
     let (input, lines) = many0(preceded(tag(prefix), set_item_line))(input)?;
     
     let mut values: HashMap<String, String> = lines.into_iter().collect();
@@ -478,10 +1110,6 @@ fn set_item_command(input: &str) -> Result<Command> {
     let mut details = values.remove("details");
     if details.is_none() {
         details = values.remove("detail");
-    } 
-
-    for extra_name in values.keys(){
-        ConsoleService::error(&format!{"Unexpected key {} found in item data for {}", extra_name, key})
     }
 
     Ok((input, Command::SetItem(Item{
@@ -491,15 +1119,25 @@ fn set_item_command(input: &str) -> Result<Command> {
     })))
 }
 
-fn set_item_line(input: &str) -> Result<(String, String)> {
+fn set_item_line(input: &str) -> ParseResult<'_, (String, String)> {
     terminated(
-        separated_pair(symbol, ws(tag(":")), raw_text_fragment),
+        separated_pair(item_field, ws(tag(":")), map(raw_text_fragment, |text| text.trim_end().to_string())),
         many1(line_end),
     )(input)
 }
 
-// item_command = ${"item" ~ (whitespace? ~ ("+" | "-") ~ whitespace? ~ symbol)+ }
-fn item_command(input: &str) -> Result<Command> {
+fn item_field(input: &str) -> ParseResult<'_, String> {
+    let (rest, name) = symbol(input)?;
+    match name.as_str() {
+        "name" | "details" | "detail" => Ok((rest, name)),
+        _ => Err(Err::Failure(VerboseError{
+            errors: vec![(input, VerboseErrorKind::Context("unknown item field, expected 'name' or 'details'"))]
+        })),
+    }
+}
+
+// item_command = ${"item" ~ item_change+ }
+fn item_command(input: &str) -> ParseResult<'_, Command> {
     let (input, (_, parts)) = pair(tag("item"), many0(item_change))(input)?;
     let mut change = HashMap::new();
     for (name, value) in parts {
@@ -515,106 +1153,219 @@ fn item_command(input: &str) -> Result<Command> {
     Ok((input, Command::Item(ItemCommand{change})))
 }
 
-fn end_command(input: &str) -> Result<Command> {
+fn end_command(input: &str) -> ParseResult<'_, Command> {
     let (input, _) = tag("end")(input)?;
     Ok((input, Command::End))
 }
 
-fn reset_command(input: &str) -> Result<Command> {
+fn reset_command(input: &str) -> ParseResult<'_, Command> {
     let (input, _) = tag("reset")(input)?;
     Ok((input, Command::Reset))
 }
 
-fn next_command(input: &str) -> Result<Command> {
+fn next_command(input: &str) -> ParseResult<'_, Command> {
     let (input, (_, _, label)) = tuple((tag("next"), skip_ws, symbol))(input)?;
     Ok((input, Command::Next(label)))
 }
 
-fn set_command(input: &str) -> Result<Command> {
-    let (input, (_, _, name, value)) = tuple((tag("set"), skip_ws, var_symbol, opt(pair(assign_operator, int_literal))))(input)?;
-    let value: i32 = match value {
-        Some((_, value)) => if let FilterOperation::IntLiteral(literal) = value {
-            literal
-        } else {
-            1
-        },
+fn call_command(input: &str) -> ParseResult<'_, Command> {
+    let (input, (_, _, label)) = tuple((tag("call"), skip_ws, symbol))(input)?;
+    Ok((input, Command::Call(label)))
+}
+
+fn return_command(input: &str) -> ParseResult<'_, Command> {
+    let (input, _) = tag("return")(input)?;
+    Ok((input, Command::Return))
+}
+
+fn set_command(input: &str) -> ParseResult<'_, Command> {
+    let (input, (_, _, name, assignment)) = tuple((
+        tag("set"), skip_ws, var_symbol, opt(pair(assign_operator, cut(context("value", filter_expr))))
+    ))(input)?;
+    let (operator, value) = assignment.unwrap_or((SetOperator::Assign, FilterOperation::IntLiteral(1)));
+    Ok((input, Command::Set(SetCommand{ name, operator, value })))
+}
+
+// The most dice one roll can throw, each one takes a random number
+const MAX_DICE: i32 = 1000;
+
+// roll = ${ "roll" ~ whitespace+ ~ var_symbol ~ "=" ~ int? ~ "d" ~ int ~ (("+" | "-") ~ int)? }
+fn roll_command(input: &str) -> ParseResult<'_, Command> {
+    let (input, (_, _, name)) = tuple((tag("roll"), many1(tag(" ")), var_symbol))(input)?;
+    let dice = input;
+    let (input, (_, _, count, _, sides, modifier)) = cut(context("dice", tuple((
+        skip_ws, char('='), preceded(skip_ws, opt(digit1)), char('d'), digit1,
+        opt(recognize(pair(alt((tag("+"), tag("-"))), digit1))),
+    ))))(input)?;
+
+    let count = match count {
+        Some(count) => parse_number(dice, count, 0..=MAX_DICE, "at most 1000 dice can be rolled at once")?,
         None => 1,
     };
-    Ok((input, Command::Set(SetCommand{ name, value  })))
+    let sides = parse_number(dice, sides, 1..=i32::MAX, "dice need between 1 and 2147483647 sides")?;
+    let modifier = match modifier {
+        Some(modifier) => parse_number(dice, modifier, i32::MIN..=i32::MAX, "modifier is too large")?,
+        None => 0,
+    };
+    Ok((input, Command::Roll(RollCommand {
+        name,
+        count: count as u32,
+        sides: sides as u32,
+        modifier,
+    })))
 }
 
-fn assign_operator(input: &str) -> Result<()> {
-    let (input, (_, content, _)) = tuple((skip_ws, alt((tag("="), tag("="))), skip_ws))(input)?;
-    if content == "=" {
-        Ok((input, ()))
-    } else {
-        Ok((input, ()))
+// Read a number already matched somewhere in `input`, failing at it if it is
+// outside the range
+fn parse_number<'a>(input: &'a str, text: &'a str, range: RangeInclusive<i32>, message: &'static str) -> Result<i32, Err<VerboseError<&'a str>>> {
+    match text.parse() {
+        Ok(value) if range.contains(&value) => Ok(value),
+        _ => {
+            // Errors are located by how much input is left after them
+            let at = &input[text.as_ptr() as usize - input.as_ptr() as usize..];
+            Err(Err::Failure(VerboseError {
+                errors: vec![(at, VerboseErrorKind::Context(message))]
+            }))
+        },
     }
 }
 
-fn item_change(input: &str) -> Result<(String, i32)> {
-    let (input, (_, change, _, name)) = tuple((many0(tag(" ")), alt((tag("+"), tag("-"))), many0(tag(" ")), symbol))(input)?;
+// assign_operator = {"=" | "+=" | "-=" | "*="}
+fn assign_operator(input: &str) -> ParseResult<'_, SetOperator> {
+    let (input, (_, content, _)) = tuple((skip_ws, alt((tag("="), tag("+="), tag("-="), tag("*="))), skip_ws))(input)?;
+    let operator = match content {
+        "+=" => SetOperator::Add,
+        "-=" => SetOperator::Sub,
+        "*=" => SetOperator::Mul,
+        _ => SetOperator::Assign,
+    };
+    Ok((input, operator))
+}
+
+// item_change = ${ whitespace? ~ ("+" | "-") ~ whitespace? ~ (count ~ whitespace+)? ~ symbol }
+fn item_change(input: &str) -> ParseResult<'_, (String, i32)> {
+    let change_start = input;
+    let (input, (_, change, _, count, name)) = tuple((
+        skip_ws, alt((tag("+"), tag("-"))), skip_ws, opt(terminated(digit1, many1(tag(" ")))), symbol
+    ))(input)?;
+    let count = match count {
+        Some(count) => parse_number(change_start, count, 0..=i32::MAX, "item count is too large")?,
+        None => 1,
+    };
     let value = if change == "+" {
-        1
+        count
     } else {
-        0
+        -count
     };
     Ok((input, (name, value)))
 }
 
 // text_line = ${ line_filter? ~ (text_fragment | link)+ }
-fn text_line(input: &str) -> Result<Entry> {
+fn text_line(input: &str) -> ParseResult<'_, Entry> {
+    let position = input.len();
     let (input, (include, filter, body)) = tuple((include_operator, opt(line_filter), many1(text_part)))(input)?;
-    Ok((input, Entry::Line(Line::TextLine(TextLine{filter, include_in_summary: include, parts: body}))))
+    Ok((input, Entry::Line(Line::TextLine(TextLine{filter, include_in_summary: include, parts: body}), position)))
 }
 
-fn include_operator(input: &str) -> Result<bool> {
+fn include_operator(input: &str) -> ParseResult<'_, bool> {
     let (input, found  ) = opt(tuple((tag("?"), skip_ws)))(input)?;
     Ok((input, found.is_some()))
 }
 
-fn text_part(input: &str) -> Result<TextPart> {
-    alt((link, text_fragment))(input)
+fn text_part(input: &str) -> ParseResult<'_, TextPart> {
+    alt((link, interpolation, text_fragment))(input)
 }
 
-// text_fragment = { (ASCII_ALPHANUMERIC | "," | "<" | "/" | ">" | "(" | ")" | " " | ".")+ }
-fn text_fragment(input: &str) -> Result<TextPart> {
-    let (input, body) = raw_text_fragment(input)?;    
-    Ok((input, TextPart::Text(body.to_string())))
+// text_fragment = { (escape | raw_span | !("[" | "]" | "{" | "}" | COMMENT | endl) ~ ANY)+ }
+fn text_fragment(input: &str) -> ParseResult<'_, TextPart> {
+    let (input, body) = text_run("[]{}/\\`\r\n")(input)?;
+    Ok((input, TextPart::Text(body)))
 }
 
-fn raw_text_fragment(input: &str) -> Result<String> {
-    let (input, body) = many1(alt((alphanumeric1, is_a(",'\"!?</>() ."))))(input)?;    
-    Ok((input, body.concat().to_string()))
+fn raw_text_fragment(input: &str) -> ParseResult<'_, String> {
+    text_run("[]/\\`\r\n")(input)
 }
 
-// link = !{ "[" ~ symbol ~ "|" ~ whitespace* ~ text_fragment ~ whitespace* ~ "]" }
-fn link(input: &str) -> Result<TextPart> {
-    let (input, (_, _, target, _, _, _, body, _, _)) = tuple((
-        tag("["), skip_ws, symbol, skip_ws, tag("|"), skip_ws, raw_text_fragment, skip_ws, tag("]")
-    ))(input)?;
+pub(crate) fn text_run<'a>(stop: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, String> {
+    move |input| {
+        let (input, body) = many1(preceded(not(comment), alt((
+            map(is_not(stop), String::from),
+            map(tag("/"), String::from),
+            escaped_char,
+            raw_span,
+        ))))(input)?;
+        Ok((input, body.concat()))
+    }
+}
+
+// interpolation = !{ "{" ~ (item_field | filter_expr) ~ "}" }
+fn interpolation(input: &str) -> ParseResult<'_, TextPart> {
+    let (input, (_, (_, value, _, _))) = pair(tag("{"), cut(context("interpolation", tuple((
+        skip_ws, alt((item_interpolation, map(filter_expr, Interpolation::Value))), skip_ws, char('}')
+    )))))(input)?;
+    Ok((input, TextPart::Interpolation(value)))
+}
+
+// item_field = ${ "item." ~ symbol ~ "." ~ ("name" | "details") }
+fn item_interpolation(input: &str) -> ParseResult<'_, Interpolation> {
+    let (input, (_, key, _, field)) = tuple((tag("item."), symbol, tag("."), alt((
+        map(tag("name"), |_| ItemField::Name),
+        map(alt((tag("details"), tag("detail"))), |_| ItemField::Details),
+    ))))(input)?;
+    Ok((input, Interpolation::Item(key, field)))
+}
+
+// escape = ${ "\\" ~ !endl ~ ANY }
+fn escaped_char(input: &str) -> ParseResult<'_, String> {
+    let (input, value) = preceded(char('\\'), cut(context("escaped character", none_of("\r\n"))))(input)?;
+    Ok((input, value.to_string()))
+}
+
+// raw_span = ${ "`" ~ (!("`" | endl) ~ ANY)* ~ "`" }
+fn raw_span(input: &str) -> ParseResult<'_, String> {
+    let (input, body) = preceded(char('`'), cut(context("raw text", terminated(opt(is_not("`\r\n")), char('`')))))(input)?;
+    Ok((input, body.unwrap_or("").to_string()))
+}
 
-    Ok((input, TextPart::Link(TextLink{destination: target, text: body})))
+// link = !{ "[" ~ ("*" | ">")? ~ symbol ~ "|" ~ whitespace* ~ text_fragment ~ whitespace* ~ "]" }
+fn link(input: &str) -> ParseResult<'_, TextPart> {
+    let (input, (_, (_, kind, target, _, _, _, body, _, _))) = pair(tag("["), cut(context("link", tuple((
+        skip_ws, opt(alt((char('*'), char('>')))), symbol, skip_ws, char('|'), skip_ws, raw_text_fragment, skip_ws, char(']')
+    )))))(input)?;
+
+    let kind = match kind {
+        Some('*') => LinkKind::Once,
+        Some(_) => LinkKind::Fallback,
+        None => LinkKind::Sticky,
+    };
+    Ok((input, TextPart::Link(TextLink{destination: target, text: body, kind, line: 0})))
 }
 
-fn skip_ws(input: &str) -> Result<()> {
+pub(crate) fn skip_ws(input: &str) -> ParseResult<'_, ()> {
     let (input, _) = many0(tag(" "))(input)?;
     Ok((input, ()))
 }
 
-// line_filter = { "(" ~ filter_expr ~ ")" }
-fn line_filter(input: &str) -> Result<LineFilter> {
-    let (input, (_, _, filter, _, _)) = tuple((tag("("), skip_ws, filter_expr, skip_ws, tag(")")))(input)?;
-    Ok((input, LineFilter{operation: filter}))
+// line_filter = { "(" ~ filter_expr ~ ("|" ~ reason?)? ~ ")" }
+fn line_filter(input: &str) -> ParseResult<'_, LineFilter> {
+    let (input, (_, _, filter, _, locked, _)) = tuple((
+        tag("("), skip_ws, filter_expr, skip_ws, opt(preceded(pair(tag("|"), skip_ws), opt(lock_reason))), tag(")")
+    ))(input)?;
+    Ok((input, LineFilter{operation: filter, locked: locked.map(Option::unwrap_or_default)}))
+}
+
+fn lock_reason(input: &str) -> ParseResult<'_, String> {
+    let (input, text) = text_run(")/\\`\r\n")(input)?;
+    Ok((input, text.trim_end().to_string()))
 }
 
 
 // filter_expr = !{ expr_equal } 
-fn filter_expr(input: &str) -> Result<FilterOperation> {
+pub(crate) fn filter_expr(input: &str) -> ParseResult<'_, FilterOperation> {
     expr_or(input)
 }
 
-fn expr_or(input: &str) -> Result<FilterOperation> {
+fn expr_or(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_and, many0(pair(or_operator, expr_and)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -627,12 +1378,12 @@ fn expr_or(input: &str) -> Result<FilterOperation> {
     Ok((input, out))
 }
 
-fn or_operator(input: &str) -> Result<Ops> {
+fn or_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, _, _)) = tuple((skip_ws, tag("or"), skip_ws))(input)?;
     Ok((input, Ops::Or))
 }
 
-fn expr_and(input: &str) -> Result<FilterOperation> {
+fn expr_and(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_equal, many0(pair(and_operator, expr_equal)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -646,14 +1397,14 @@ fn expr_and(input: &str) -> Result<FilterOperation> {
 }
 
 // equal_operator = {"=" | "!="}
-fn and_operator(input: &str) -> Result<Ops> {
+fn and_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, _, _)) = tuple((skip_ws, tag("and"), skip_ws))(input)?;
     Ok((input, Ops::And))
 }
 
 
 // expr_equal = { expr_comp ~ (equal_operator ~ expr_comp)*}
-fn expr_equal(input: &str) -> Result<FilterOperation> {
+fn expr_equal(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_comp, many0(pair(equal_operator, expr_comp)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -667,7 +1418,7 @@ fn expr_equal(input: &str) -> Result<FilterOperation> {
 }
 
 // equal_operator = {"=" | "!="}
-fn equal_operator(input: &str) -> Result<Ops> {
+fn equal_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, content, _)) = tuple((skip_ws, alt((tag("!="), tag("=="), tag("="))), skip_ws))(input)?;
     if content == "=" || content == "==" {
         Ok((input, Ops::Eq))
@@ -677,7 +1428,7 @@ fn equal_operator(input: &str) -> Result<Ops> {
 }
 
 // expr_comp = { expr_sum ~ (comp_operator ~ expr_sum)*}
-fn expr_comp(input: &str) -> Result<FilterOperation> {
+fn expr_comp(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_sum, many0(pair(comp_operator, expr_sum)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -691,7 +1442,7 @@ fn expr_comp(input: &str) -> Result<FilterOperation> {
 }
 
 // comp_operator = {">=" | ">" | "<" | "<="}
-fn comp_operator(input: &str) -> Result<Ops> {
+fn comp_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, content, _)) = tuple((skip_ws, alt((tag(">="), tag("<="), tag(">"), tag("<"))), skip_ws))(input)?;
     if content == ">=" {
         Ok((input, Ops::Gte))
@@ -706,7 +1457,7 @@ fn comp_operator(input: &str) -> Result<Ops> {
 
 
 // expr_sum = { expr_prod ~ (sum_operator ~ expr_prod )* }
-fn expr_sum(input: &str) -> Result<FilterOperation> {
+fn expr_sum(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_prod, many0(pair(sum_operator, expr_prod)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -720,7 +1471,7 @@ fn expr_sum(input: &str) -> Result<FilterOperation> {
 }
 
 // sum_operator = {"+" | "-"}
-fn sum_operator(input: &str) -> Result<Ops> {
+fn sum_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, content, _)) = tuple((skip_ws, alt((tag("+"), tag("-"))), skip_ws))(input)?;
     if content == "+" {
         Ok((input, Ops::Add))
@@ -730,7 +1481,7 @@ fn sum_operator(input: &str) -> Result<Ops> {
 }
 
 // expr_prod = { expr_atom ~ (prod_operator ~ expr_atom )* }
-fn expr_prod(input: &str) -> Result<FilterOperation> {
+fn expr_prod(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (first, additional)) = pair(expr_atom, many0(pair(prod_operator, expr_atom)))(input)?;
     let mut out = first;
     for (op, expr) in additional {
@@ -744,7 +1495,7 @@ fn expr_prod(input: &str) -> Result<FilterOperation> {
 }
 
 // prod_operator = {"*" | "/"}
-fn prod_operator(input: &str) -> Result<Ops> {
+fn prod_operator(input: &str) -> ParseResult<'_, Ops> {
     let (input, (_, content, _)) = tuple((skip_ws, alt((tag("*"), tag("/"))), skip_ws))(input)?;
     if content == "*" {
         Ok((input, Ops::Mul))
@@ -754,32 +1505,227 @@ fn prod_operator(input: &str) -> Result<Ops> {
 }
 
 // expr_atom = _{ ("(" ~ filter_expr ~ ")") | count_visits | int_literal }
-fn expr_atom(input: &str) -> Result<FilterOperation> {
-    alt((sub_expr, int_literal, count_visits, count_items, read_variable))(input)
+fn expr_atom(input: &str) -> ParseResult<'_, FilterOperation> {
+    alt((sub_expr, int_literal, count_visits, count_items, random_call, read_variable))(input)
 }
 
-fn sub_expr(input: &str) -> Result<FilterOperation> {
+// random_call = { "rand" ~ "(" ~ filter_expr ~ "," ~ filter_expr ~ ")" }
+fn random_call(input: &str) -> ParseResult<'_, FilterOperation> {
+    let (input, _) = tuple((tag("rand"), skip_ws, tag("(")))(input)?;
+    let (input, (_, low, _, _, _, high, _, _)) = cut(context("rand arguments", tuple((
+        skip_ws, filter_expr, skip_ws, char(','), skip_ws, filter_expr, skip_ws, char(')')
+    ))))(input)?;
+    Ok((input, FilterOperation::Random(Box::new(RandomCall{ low, high }))))
+}
+
+fn sub_expr(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (_, expr, _)) = tuple((tag("("), filter_expr, tag(")")))(input)?;
     Ok((input, expr))
 }
 
-fn count_items(input: &str) -> Result<FilterOperation> {
+fn count_items(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, (_, content)) = pair(tag("$"), symbol)(input)?;
     Ok((input, FilterOperation::CountItems(content)))
 }
 
-fn count_visits(input: &str) -> Result<FilterOperation> {
-    let (input, (_, content)) = pair(tag("#"), symbol)(input)?;
-    Ok((input, FilterOperation::CountVisits(content)))
+// count_visits = ${ "#" ~ symbol ~ ("." ~ symbol)* }
+fn count_visits(input: &str) -> ParseResult<'_, FilterOperation> {
+    let (input, (_, content)) = pair(tag("#"), recognize(separated_list1(char('.'), symbol)))(input)?;
+    Ok((input, FilterOperation::CountVisits(content.to_string())))
 }
 
-fn read_variable(input: &str) -> Result<FilterOperation> {
+fn read_variable(input: &str) -> ParseResult<'_, FilterOperation> {
     let (input, content) = var_symbol(input)?;
     Ok((input, FilterOperation::ReadVariable(content)))
 }
 
 // int_literal = { '1'..'9' ~ '0'..'9'* }
-fn int_literal(input: &str) -> Result<FilterOperation> {
-    let (input, content) = digit1(input)?;
-    Ok((input, FilterOperation::IntLiteral(content.parse().unwrap())))
+fn int_literal(input: &str) -> ParseResult<'_, FilterOperation> {
+    let (rest, content) = digit1(input)?;
+    let value = parse_number(input, content, 0..=i32::MAX, "number is too large")?;
+    Ok((rest, FilterOperation::IntLiteral(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> ZoneError {
+        build_world(source.to_string()).unwrap_err()
+    }
+
+    fn commands(source: &str) -> Vec<Command> {
+        let zone = build_world(source.to_string()).unwrap();
+        zone.scenes()[0].lines.iter().filter_map(|line| match line {
+            Line::CommandLine(line) => Some(line.command.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn scenes_are_labelled_by_nesting() {
+        let zone = build_world(String::from("default: ??\n    ? Pick.\n    [a | A]\n\n    a:\n        In a.\n        *end\n")).unwrap();
+        let labels: Vec<&str> = zone.scenes().iter().map(|scene| scene.label.as_str()).collect();
+        assert_eq!(labels, vec!["default", "default.a"]);
+        assert!(zone.scenes()[0].branch);
+        assert_eq!(zone.scene("default.a").unwrap().source_line, 5);
+        assert_eq!(zone.following("default.a"), Some(String::from("default")));
+    }
+
+    // The parts of the first line of the first scene, with text written out
+    fn parts(source: &str) -> Vec<String> {
+        let zone = build_world(source.to_string()).unwrap();
+        match &zone.scenes()[0].lines[0] {
+            Line::TextLine(line) => line.parts.iter().map(|part| match part {
+                TextPart::Text(text) => text.clone(),
+                TextPart::Link(link) => format!("[{}]", link.destination),
+                TextPart::Interpolation(_) => String::from("{}"),
+            }).collect(),
+            _ => panic!("expected a text line"),
+        }
+    }
+
+    #[test]
+    fn escapes_are_plain_text() {
+        assert_eq!(parts("default:\n    \\* a \\{b\\} \\[c\\] \\\\ d\n    *end\n"), vec!["* a {b} [c] \\ d"]);
+        assert_eq!(parts("default:\n    \\(aside) \\/\\/ not a comment\n    *end\n"), vec!["(aside) // not a comment"]);
+    }
+
+    #[test]
+    fn raw_spans_are_plain_text() {
+        assert_eq!(parts("default:\n    `[not | a link] {x}` then [a | a link]\n    *end\n\na:\n    *end\n"), vec!["[not | a link] {x} then ", "[a]"]);
+    }
+
+    #[test]
+    fn graph_edges_carry_block_conditions() {
+        let zone = build_world(String::from("default:\n    *if x:\n        [a | A]\n    *elif y:\n        *next b\n    *else:\n        [b | B]\n\na:\n    *end\n\nb:\n    *end\n")).unwrap();
+        let dot = zone.to_dot();
+        assert!(dot.contains(r#""default" -> "a" [label="A if x"];"#));
+        assert!(dot.contains(r#""default" -> "b" [label="*next if not (x) and y", style=bold];"#));
+        assert!(dot.contains(r#""default" -> "b" [label="B if not (x) and not (y)"];"#));
+    }
+
+    #[test]
+    fn item_fields_stop_before_comments() {
+        match &commands("default:\n    *set_item hat\n        name: Hat  // a plain one\n        details: Warm.\n    Hi.\n")[0] {
+            Command::SetItem(item) => {
+                assert_eq!(item.name.as_deref(), Some("Hat"));
+                assert_eq!(item.details.as_deref(), Some("Warm."));
+            },
+            command => panic!("expected an item, got {:?}", command),
+        }
+    }
+
+    // Assemble a zone from named files, the first being the root
+    fn assembled(sources: &[(&str, &str)]) -> Result<Rc<Zone>, ZoneError> {
+        let mut files = HashMap::new();
+        for (name, source) in sources {
+            files.insert(name.to_string(), ZoneFile::parse(name.to_string(), source.to_string())?);
+        }
+        assemble(sources[0].0, &files, |_, path| path.to_string())
+    }
+
+    #[test]
+    fn includes_add_their_scenes() {
+        let zone = assembled(&[
+            ("main.zone", "default:\n    Start.\n    *next forest\n\n*include forest.zone\n"),
+            ("forest.zone", "forest:\n    Trees.\n    *end\n"),
+        ]).unwrap();
+        let forest = zone.scene("forest").unwrap();
+        assert_eq!((forest.file.as_str(), forest.source_line), ("forest.zone", 1));
+    }
+
+    #[test]
+    fn missing_includes_are_located() {
+        let err = assembled(&[("main.zone", "default:\n    Start.\n    *end\n\n*include gone.zone\n")]).unwrap_err();
+        assert_eq!((err.file.as_str(), err.line, err.column), ("main.zone", 5, 1));
+        assert_eq!(err.message, "included file 'gone.zone' could not be loaded");
+    }
+
+    #[test]
+    fn include_cycles_are_merged_once() {
+        let zone = assembled(&[
+            ("a.zone", "default:\n    A.\n    *next b\n\n*include b.zone\n"),
+            ("b.zone", "*include a.zone\n\nb:\n    B.\n    *end\n"),
+        ]).unwrap();
+        let labels: Vec<&str> = zone.scenes().iter().map(|scene| scene.label.as_str()).collect();
+        assert_eq!(labels, vec!["default", "b"]);
+    }
+
+    #[test]
+    fn labels_are_unique_across_files() {
+        let err = assembled(&[
+            ("main.zone", "default:\n    Start.\n    *end\n\n*include other.zone\n"),
+            ("other.zone", "// the same again\ndefault:\n    Again.\n    *end\n"),
+        ]).unwrap_err();
+        assert_eq!((err.file.as_str(), err.line), ("other.zone", 2));
+        assert_eq!(err.message, "scene 'default' is already defined in main.zone");
+    }
+
+    #[test]
+    fn header_describes_the_story() {
+        let zone = build_world(String::from("*title Stones  // working title\n*author Someone\n*version 2\n*description A walk.\n*start river\n---\nriver:\n    Wet.\n    *end\n")).unwrap();
+        assert_eq!(zone.meta.title.as_deref(), Some("Stones"));
+        assert_eq!(zone.meta.author.as_deref(), Some("Someone"));
+        assert_eq!(zone.meta.version.as_deref(), Some("2"));
+        assert_eq!(zone.meta.description.as_deref(), Some("A walk."));
+        assert_eq!(zone.start(), "river");
+    }
+
+    #[test]
+    fn missing_start_scene_is_an_error() {
+        let err = error("elsewhere:\n    Hi.\n    *end\n");
+        assert_eq!(err.message, "start scene 'default' does not exist");
+
+        let err = error("*title Stones\n*start river\n---\ndefault:\n    Hi.\n    *end\n");
+        assert_eq!((err.line, err.message.as_str()), (2, "start scene 'river' does not exist"));
+    }
+
+    #[test]
+    fn only_the_root_header_counts() {
+        let zone = assembled(&[
+            ("main.zone", "*title Main\n---\ndefault:\n    Hi.\n    *next other\n\n*include other.zone\n"),
+            ("other.zone", "*title Other\n*start other\n---\nother:\n    There.\n    *end\n"),
+        ]).unwrap();
+        assert_eq!(zone.meta.title.as_deref(), Some("Main"));
+        assert_eq!(zone.start(), "default");
+    }
+
+    #[test]
+    fn positions_become_line_numbers() {
+        let source = "one\ntwo\n\nfour";
+        let lines = LineNumbers::new(source);
+        let at = |text: &str| lines.line(source.len() - source.find(text).unwrap());
+        assert_eq!((at("one"), at("two"), at("\nfour"), at("four")), (1, 2, 3, 4));
+        assert_eq!(lines.line(0), 4);
+    }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let err = error("default:\n    *set x = (1 +\n    *end\n");
+        assert_eq!((err.line, err.column), (2, 14));
+    }
+
+    #[test]
+    fn large_numbers_are_errors() {
+        let err = error("default:\n    hi {99999999999}\n    *end\n");
+        assert_eq!((err.line, err.column), (2, 9));
+        assert!(err.message.starts_with("number is too large"));
+
+        let err = error("default:\n    *item +99999999999 gold\n    *end\n");
+        assert!(err.message.starts_with("item count is too large"));
+    }
+
+    #[test]
+    fn dice_are_checked() {
+        match &commands("default:\n    *roll x = 3d6-2147483648\n    *end\n")[0] {
+            Command::Roll(roll) => assert_eq!((roll.count, roll.sides, roll.modifier), (3, 6, i32::MIN)),
+            _ => panic!("expected a roll"),
+        }
+        let err = error("default:\n    *roll x = 4000000000d6\n    *end\n");
+        assert_eq!((err.line, err.column), (2, 15));
+        assert!(err.message.starts_with("at most 1000 dice"));
+        assert!(error("default:\n    *roll x = d6+3000000000\n    *end\n").message.starts_with("modifier is too large"));
+        assert!(error("default:\n    *roll x = d0\n    *end\n").message.starts_with("dice need"));
+    }
 }
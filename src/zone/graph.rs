@@ -0,0 +1,165 @@
+use std::fmt::Write;
+
+use super::lint::falls_through;
+use super::{Command, LinkKind, Line, LineFilter, TextPart, Zone};
+
+enum EdgeKind {
+    Link,
+    Command,
+    FallThrough,
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: String,
+    kind: EdgeKind,
+}
+
+// The `*if` and `*elif` conditions each line of a scene is nested under. A
+// guarded jump skips its branch when the condition fails, and the jump ending
+// a branch skips every later one, so those only run when it failed.
+fn conditions(lines: &[Line]) -> Vec<Vec<String>> {
+    let mut conditions = vec![Vec::new(); lines.len()];
+    for (index, line) in lines.iter().enumerate() {
+        let jump = match line {
+            Line::Jump(jump) => jump,
+            _ => continue,
+        };
+        let condition = match &jump.unless {
+            Some(filter) => filter.operation.to_string(),
+            None => {
+                let guard = lines[..index].iter().rev().find_map(|line| match line {
+                    Line::Jump(guard) if guard.target == index + 1 => guard.unless.as_ref(),
+                    _ => None,
+                });
+                match guard {
+                    Some(filter) => format!("not ({})", filter.operation),
+                    None => continue,
+                }
+            },
+        };
+        for covered in conditions.iter_mut().take(jump.target).skip(index + 1) {
+            covered.push(condition.clone());
+        }
+    }
+    conditions
+}
+
+fn with_filter(label: String, conditions: &[String], filter: &Option<LineFilter>) -> String {
+    let mut all = conditions.to_vec();
+    if let Some(filter) = filter {
+        all.push(filter.operation.to_string());
+    }
+    if all.is_empty() {
+        label
+    } else {
+        format!("{} if {}", label, all.join(" and "))
+    }
+}
+
+impl Zone {
+    // Every way play can move from one scene to another
+    fn flow_edges(&self) -> Vec<Edge> {
+        let mut edges = Vec::new();
+        for (from, scene) in self.scenes.iter().enumerate() {
+            let mut add = |target: &str, label: String, kind: EdgeKind| {
+                if let Some(&to) = self.lookup.get(target) {
+                    edges.push(Edge { from, to, label, kind });
+                }
+            };
+
+            let conditions = conditions(&scene.lines);
+            for (line, conditions) in scene.lines.iter().zip(&conditions) {
+                match line {
+                    Line::TextLine(text) => {
+                        for part in &text.parts {
+                            if let TextPart::Link(link) = part {
+                                let label = match link.kind {
+                                    LinkKind::Sticky => link.text.clone(),
+                                    LinkKind::Once => format!("{} (once)", link.text),
+                                    LinkKind::Fallback => format!("{} (fallback)", link.text),
+                                };
+                                add(&link.destination, with_filter(label, conditions, &text.filter), EdgeKind::Link);
+                            }
+                        }
+                    },
+                    Line::CommandLine(line) => match &line.command {
+                        Command::Next(target) => add(target, with_filter(String::from("*next"), conditions, &line.filter), EdgeKind::Command),
+                        Command::Call(target) => add(target, with_filter(String::from("*call"), conditions, &line.filter), EdgeKind::Command),
+                        _ => {},
+                    },
+                    // Folded into the conditions of the lines they guard
+                    Line::Jump(_) => {},
+                }
+            }
+
+            if !scene.branch && falls_through(scene, false) {
+                if let Some(next) = self.following(&scene.label) {
+                    add(&next, String::new(), EdgeKind::FallThrough);
+                }
+            }
+        }
+        edges
+    }
+
+    /// The flow between scenes as a Graphviz DOT graph.
+    pub fn to_dot(&self) -> String {
+        let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+        let start = self.start();
+
+        let mut out = String::from("digraph zone {\n    node [shape=box];\n");
+        for scene in &self.scenes {
+            let mut style = Vec::new();
+            if scene.branch {
+                style.push("shape=diamond");
+            }
+            if scene.label == start {
+                style.push("peripheries=2");
+            }
+            if style.is_empty() {
+                writeln!(out, "    {};", quote(&scene.label)).unwrap();
+            } else {
+                writeln!(out, "    {} [{}];", quote(&scene.label), style.join(", ")).unwrap();
+            }
+        }
+        for edge in self.flow_edges() {
+            let from = quote(&self.scenes[edge.from].label);
+            let to = quote(&self.scenes[edge.to].label);
+            match edge.kind {
+                EdgeKind::Link => writeln!(out, "    {} -> {} [label={}];", from, to, quote(&edge.label)),
+                EdgeKind::Command => writeln!(out, "    {} -> {} [label={}, style=bold];", from, to, quote(&edge.label)),
+                EdgeKind::FallThrough => writeln!(out, "    {} -> {} [style=dashed];", from, to),
+            }.unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The flow between scenes as a Mermaid flowchart.
+    pub fn to_mermaid(&self) -> String {
+        let quote = |text: &str| format!("\"{}\"", text.replace('"', "#quot;"));
+        let start = self.start();
+
+        let mut out = String::from("flowchart TD\n");
+        for (index, scene) in self.scenes.iter().enumerate() {
+            if scene.branch {
+                writeln!(out, "    n{}{{{}}}", index, quote(&scene.label)).unwrap();
+            } else {
+                writeln!(out, "    n{}[{}]", index, quote(&scene.label)).unwrap();
+            }
+        }
+        for edge in self.flow_edges() {
+            match edge.kind {
+                EdgeKind::Link => writeln!(out, "    n{} -->|{}| n{}", edge.from, quote(&edge.label), edge.to),
+                EdgeKind::Command => writeln!(out, "    n{} ==>|{}| n{}", edge.from, quote(&edge.label), edge.to),
+                EdgeKind::FallThrough => writeln!(out, "    n{} -.-> n{}", edge.from, edge.to),
+            }.unwrap();
+        }
+        if let Some(index) = self.lookup.get(&start) {
+            writeln!(out, "    classDef start stroke-width:3px").unwrap();
+            writeln!(out, "    class n{} start", index).unwrap();
+        }
+        out
+    }
+}
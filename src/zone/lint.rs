@@ -0,0 +1,402 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use super::{write_location, Command, FilterOperation, Interpolation, Line, Scene, TextPart, Zone};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a zone that parsed, located in the source text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, &self.file, self.line)?;
+        write!(f, ": {}: {}", self.severity, self.message)
+    }
+}
+
+/// Look for mistakes that parse fine but go wrong while playing, such as
+/// links to missing scenes or scenes that can never be reached.
+pub fn lint(zone: &Zone) -> Vec<Diagnostic> {
+    let mut lint = Lint {
+        zone,
+        diagnostics: Vec::new(),
+    };
+    lint.check_labels();
+    lint.check_targets();
+    lint.check_flow();
+    lint.check_values();
+
+    let mut diagnostics = lint.diagnostics;
+    diagnostics.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    diagnostics
+}
+
+struct Lint<'a> {
+    zone: &'a Zone,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Lint<'a> {
+    fn report(&mut self, severity: Severity, at: (&str, usize), message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file: at.0.to_string(),
+            line: at.1,
+            message,
+        });
+    }
+
+    fn check_labels(&mut self) {
+        let mut seen: HashMap<&str, &Scene> = HashMap::new();
+        for scene in &self.zone.scenes {
+            match seen.get(scene.label.as_str()) {
+                Some(first) => {
+                    let at = match first.file.as_str() {
+                        "" => format!("line {}", first.source_line),
+                        file => format!("{}, line {}", file, first.source_line),
+                    };
+                    let message = format!("scene '{}' is already defined at {}", scene.label, at);
+                    self.report(Severity::Error, (&scene.file, scene.source_line), message);
+                },
+                None => {
+                    seen.insert(&scene.label, scene);
+                },
+            }
+        }
+    }
+
+    fn check_targets(&mut self) {
+        let zone = self.zone;
+        for scene in &zone.scenes {
+            for (index, line) in scene.lines.iter().enumerate() {
+                let at = (scene.file.as_str(), scene.line_sources[index]);
+                for (kind, target) in targets(line) {
+                    if !zone.lookup.contains_key(target) {
+                        self.report(Severity::Error, at, format!("{} undefined scene '{}'", kind, target));
+                    }
+                }
+                for op in expressions(line) {
+                    walk(op, &mut |op| {
+                        if let FilterOperation::CountVisits(target) = op {
+                            if !zone.lookup.contains_key(target) {
+                                self.report(Severity::Warning, at, format!("visit count of undefined scene '{}' is always 0", target));
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_flow(&mut self) {
+        let zone = self.zone;
+        let mut reached = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(zone.start());
+        while let Some(label) = queue.pop_front() {
+            let scene = match zone.lookup.get(&label) {
+                Some(&index) => &zone.scenes[index],
+                None => continue,
+            };
+            if !reached.insert(label) {
+                continue;
+            }
+            for line in &scene.lines {
+                for (_, target) in targets(line) {
+                    queue.push_back(target.clone());
+                }
+            }
+            // A *return with nothing to return to also carries on to the next scene
+            if !scene.branch && falls_through(scene, true) {
+                queue.extend(zone.following(&scene.label));
+            }
+        }
+
+        for scene in &zone.scenes {
+            let at = (scene.file.as_str(), scene.source_line);
+            if !scene.branch && falls_through(scene, false) && zone.following(&scene.label).is_none() {
+                let message = format!("scene '{}' can run past the end of the zone, finish it with *next, *end or *reset", scene.label);
+                self.report(Severity::Error, at, message);
+            }
+            if !reached.contains(&scene.label) {
+                self.report(Severity::Warning, at, format!("scene '{}' can never be reached", scene.label));
+            }
+        }
+    }
+
+    fn check_values(&mut self) {
+        let zone = self.zone;
+        let mut set = HashSet::new();
+        let mut given = HashSet::new();
+        let mut defined = HashSet::new();
+        let mut reads = Vec::new();
+        let mut counted = Vec::new();
+        let mut shown = Vec::new();
+
+        // Header commands have no line of their own, count them as the top of the root file
+        let header = zone.scenes.first().map(|scene| (scene.file.as_str(), 1)).unwrap_or(("", 1));
+        let header_lines = zone.initialize.iter().map(|command| (header, command_values(command)));
+        let scene_lines = zone.scenes.iter().flat_map(|scene| {
+            scene.lines.iter().enumerate().map(move |(index, line)| {
+                let at = (scene.file.as_str(), scene.line_sources[index]);
+                let values = match line {
+                    Line::CommandLine(line) => command_values(&line.command),
+                    Line::TextLine(text) => Values {
+                        shown: text.parts.iter().filter_map(|part| match part {
+                            TextPart::Interpolation(Interpolation::Item(key, _)) => Some(key),
+                            _ => None,
+                        }).collect(),
+                        ..Values::default()
+                    },
+                    Line::Jump(_) => Values::default(),
+                };
+                (at, Values { expressions: expressions(line), ..values })
+            })
+        });
+
+        for (at, values) in header_lines.chain(scene_lines) {
+            set.extend(values.set);
+            given.extend(values.given);
+            defined.extend(values.defined);
+            counted.extend(values.removed.into_iter().map(|item| (at, item)));
+            for op in values.expressions {
+                walk(op, &mut |op| match op {
+                    FilterOperation::ReadVariable(name) => reads.push((at, name)),
+                    FilterOperation::CountItems(item) => counted.push((at, item)),
+                    _ => {},
+                });
+            }
+            shown.extend(values.shown.into_iter().map(|item| (at, item)));
+        }
+
+        let mut reported = HashSet::new();
+        for (at, name) in reads {
+            if !set.contains(name) && reported.insert(("variable", name)) {
+                self.report(Severity::Warning, at, format!("variable '{}' is read but never set, so it is always 0", name));
+            }
+        }
+        for (at, item) in counted {
+            if !given.contains(item) && reported.insert(("given", item)) {
+                self.report(Severity::Warning, at, format!("item '{}' is used but never given with *item", item));
+            }
+        }
+        for (at, item) in shown {
+            if !defined.contains(item) && reported.insert(("defined", item)) {
+                self.report(Severity::Warning, at, format!("item '{}' is shown but never defined with *set_item", item));
+            }
+        }
+    }
+}
+
+// Scenes a line can send play to, along with how it does so
+fn targets(line: &Line) -> Vec<(&'static str, &String)> {
+    match line {
+        Line::TextLine(text) => text.parts.iter().filter_map(|part| match part {
+            TextPart::Link(link) => Some(("link to", &link.destination)),
+            _ => None,
+        }).collect(),
+        Line::CommandLine(line) => match &line.command {
+            Command::Next(target) => vec![("*next to", target)],
+            Command::Call(target) => vec![("*call to", target)],
+            _ => vec![],
+        },
+        Line::Jump(_) => vec![],
+    }
+}
+
+// Every expression written on a line
+pub(crate) fn expressions(line: &Line) -> Vec<&FilterOperation> {
+    let mut out = Vec::new();
+    match line {
+        Line::TextLine(text) => {
+            out.extend(text.filter.iter().map(|filter| &filter.operation));
+            for part in &text.parts {
+                if let TextPart::Interpolation(Interpolation::Value(op)) = part {
+                    out.push(op);
+                }
+            }
+        },
+        Line::CommandLine(line) => {
+            out.extend(line.filter.iter().map(|filter| &filter.operation));
+            if let Command::Set(cmd) = &line.command {
+                out.push(&cmd.value);
+            }
+        },
+        Line::Jump(jump) => out.extend(jump.unless.iter().map(|filter| &filter.operation)),
+    }
+    out
+}
+
+#[derive(Default)]
+struct Values<'a> {
+    expressions: Vec<&'a FilterOperation>,
+    set: Vec<&'a String>,
+    given: Vec<&'a String>,
+    removed: Vec<&'a String>,
+    defined: Vec<&'a String>,
+    shown: Vec<&'a String>,
+}
+
+// What a command reads and writes, besides its expressions
+fn command_values(command: &Command) -> Values<'_> {
+    let mut values = Values::default();
+    match command {
+        Command::Set(cmd) => {
+            values.set.push(&cmd.name);
+            values.expressions.push(&cmd.value);
+        },
+        Command::Roll(cmd) => values.set.push(&cmd.name),
+        Command::SetItem(item) => values.defined.push(&item.key),
+        Command::Item(items) => {
+            for (key, change) in &items.change {
+                if *change > 0 {
+                    values.given.push(key);
+                } else {
+                    values.removed.push(key);
+                }
+            }
+        },
+        _ => {},
+    }
+    values
+}
+
+pub(crate) fn walk<'a>(op: &'a FilterOperation, visit: &mut impl FnMut(&'a FilterOperation)) {
+    visit(op);
+    match op {
+        FilterOperation::OperatorCall(call) => {
+            walk(&call.left, visit);
+            walk(&call.right, visit);
+        },
+        FilterOperation::Random(call) => {
+            walk(&call.low, visit);
+            walk(&call.high, visit);
+        },
+        _ => {},
+    }
+}
+
+// Whether play can run off the last line of a non-branch scene
+pub(super) fn falls_through(scene: &Scene, past_return: bool) -> bool {
+    let mut todo = vec![0];
+    let mut seen = HashSet::new();
+    while let Some(index) = todo.pop() {
+        if index >= scene.lines.len() {
+            return true;
+        }
+        if !seen.insert(index) {
+            continue;
+        }
+        match &scene.lines[index] {
+            Line::TextLine(_) => todo.push(index + 1),
+            Line::CommandLine(line) => {
+                let stops = match line.command {
+                    Command::Next(_) | Command::End | Command::Reset => true,
+                    Command::Return => !past_return,
+                    _ => false,
+                };
+                if line.filter.is_some() || !stops {
+                    todo.push(index + 1);
+                }
+            },
+            Line::Jump(jump) => {
+                todo.push(jump.target);
+                if jump.unless.is_some() {
+                    todo.push(index + 1);
+                }
+            },
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::zone::{assemble, build_world, ZoneFile};
+
+    fn diagnostics(source: &str) -> Vec<String> {
+        lint(&build_world(source.to_string()).unwrap()).iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn undefined_targets() {
+        assert_eq!(diagnostics("default:\n    [gone | Go.]\n    *next lost\n    *call missing\n    *end\n"), vec![
+            "line 2: error: link to undefined scene 'gone'",
+            "line 3: error: *next to undefined scene 'lost'",
+            "line 4: error: *call to undefined scene 'missing'",
+        ]);
+    }
+
+    #[test]
+    fn duplicate_labels() {
+        let mut files = HashMap::new();
+        let source = "default:\n    [extra | Go.]\n\nextra:\n    *end\n\nextra:\n    *end\n";
+        files.insert(String::from("main.zone"), ZoneFile::parse(String::from("main.zone"), source.to_string()).unwrap());
+        let zone = assemble("main.zone", &files, |_, path| path.to_string()).unwrap();
+        let diagnostics: Vec<String> = lint(&zone).iter().map(Diagnostic::to_string).collect();
+        assert_eq!(diagnostics, vec!["main.zone, line 7: error: scene 'extra' is already defined at main.zone, line 4"]);
+    }
+
+    #[test]
+    fn unreachable_scenes() {
+        assert_eq!(diagnostics("default:\n    *end\n\nlost:\n    *end\n"), vec!["line 4: warning: scene 'lost' can never be reached"]);
+    }
+
+    #[test]
+    fn scenes_running_past_the_end() {
+        assert_eq!(diagnostics("default:\n    *next last\n\nlast:\n    Then nothing.\n"), vec![
+            "line 4: error: scene 'last' can run past the end of the zone, finish it with *next, *end or *reset",
+        ]);
+    }
+
+    #[test]
+    fn variables_read_but_never_set() {
+        assert_eq!(diagnostics("default:\n    (ready) Ready.\n    {ready} again.\n    *end\n"), vec![
+            "line 2: warning: variable 'ready' is read but never set, so it is always 0",
+        ]);
+    }
+
+    #[test]
+    fn items_used_but_never_given() {
+        assert_eq!(diagnostics("*set_item key\n    name: Key\n---\ndefault:\n    ($key) Open.\n    *item -1 key\n    *end\n"), vec![
+            "line 5: warning: item 'key' is used but never given with *item",
+        ]);
+    }
+
+    #[test]
+    fn items_shown_but_never_defined() {
+        assert_eq!(diagnostics("default:\n    *item +1 key\n    A {item.key.name}.\n    *end\n"), vec![
+            "line 3: warning: item 'key' is shown but never defined with *set_item",
+        ]);
+    }
+
+    #[test]
+    fn visits_of_unknown_scenes() {
+        assert_eq!(diagnostics("default:\n    (#nowhere > 0) Been.\n    *end\n"), vec![
+            "line 2: warning: visit count of undefined scene 'nowhere' is always 0",
+        ]);
+    }
+}